@@ -0,0 +1,137 @@
+use crate::fs_ops::CopyOptions;
+use neo_api_rs::mlua::prelude::*;
+use std::collections::HashMap;
+
+/// User options, set through `require('nvim-traveller-rs').setup({ ... })`
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Show every planned operation for confirmation before pasting or deleting the selection
+    pub preview_operations: bool,
+    /// Show modification times in the detail view as e.g. `3 h ago` instead of a date
+    pub relative_time: bool,
+    /// Ignore case when sorting by name or extension
+    pub sort_case_insensitive: bool,
+    /// Hide what git ignores when opening a navigation, toggled per navigation
+    pub hide_ignored: bool,
+    /// Mark the entries with their git status, right aligned
+    pub git_status: bool,
+    /// Show filetype icons in front of the names, this needs a Nerd Font
+    pub icons: bool,
+    /// Colour the names the way `ls` does, using `LS_COLORS`
+    pub ls_colors: bool,
+    /// Append `*`, `|` or `=` to executables, named pipes and sockets, like `ls -F`
+    pub type_indicators: bool,
+    /// Highlight groups by extension, these take precedence over `LS_COLORS`
+    pub colors: HashMap<String, String>,
+    /// Files above this size in bytes aren't read by the preview window
+    pub preview_max_size: u64,
+    /// Compute the total size of every listed directory in the background
+    pub dir_sizes: bool,
+    /// Open navigations with the parent directory and the preview next to the listing
+    pub miller_columns: bool,
+    /// Pasted copies keep the permission bits of the originals
+    pub copy_preserve_mode: bool,
+    /// Pasted copies keep the modification times of the originals
+    pub copy_preserve_mtime: bool,
+    /// Copy what symlinks point to instead of the links themselves
+    pub copy_follow_symlinks: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            preview_operations: false,
+            relative_time: false,
+            sort_case_insensitive: false,
+            hide_ignored: false,
+            git_status: false,
+            icons: false,
+            ls_colors: false,
+            type_indicators: false,
+            colors: HashMap::new(),
+            preview_max_size: 1024 * 1024,
+            dir_sizes: false,
+            miller_columns: false,
+            copy_preserve_mode: true,
+            copy_preserve_mtime: true,
+            copy_follow_symlinks: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn update(&mut self, opts: LuaTable) -> LuaResult<()> {
+        if let Some(preview_operations) = opts.get("preview_operations")? {
+            self.preview_operations = preview_operations;
+        }
+
+        if let Some(relative_time) = opts.get("relative_time")? {
+            self.relative_time = relative_time;
+        }
+
+        if let Some(sort_case_insensitive) = opts.get("sort_case_insensitive")? {
+            self.sort_case_insensitive = sort_case_insensitive;
+        }
+
+        if let Some(hide_ignored) = opts.get("hide_ignored")? {
+            self.hide_ignored = hide_ignored;
+        }
+
+        if let Some(git_status) = opts.get("git_status")? {
+            self.git_status = git_status;
+        }
+
+        if let Some(icons) = opts.get("icons")? {
+            self.icons = icons;
+        }
+
+        if let Some(ls_colors) = opts.get("ls_colors")? {
+            self.ls_colors = ls_colors;
+        }
+
+        if let Some(type_indicators) = opts.get("type_indicators")? {
+            self.type_indicators = type_indicators;
+        }
+
+        if let Some(colors) = opts.get::<_, Option<HashMap<String, String>>>("colors")? {
+            self.colors = colors
+                .into_iter()
+                .map(|(ext, hl_group)| (ext.to_lowercase(), hl_group))
+                .collect();
+        }
+
+        if let Some(preview_max_size) = opts.get("preview_max_size")? {
+            self.preview_max_size = preview_max_size;
+        }
+
+        if let Some(dir_sizes) = opts.get("dir_sizes")? {
+            self.dir_sizes = dir_sizes;
+        }
+
+        if let Some(miller_columns) = opts.get("miller_columns")? {
+            self.miller_columns = miller_columns;
+        }
+
+        if let Some(copy_preserve_mode) = opts.get("copy_preserve_mode")? {
+            self.copy_preserve_mode = copy_preserve_mode;
+        }
+
+        if let Some(copy_preserve_mtime) = opts.get("copy_preserve_mtime")? {
+            self.copy_preserve_mtime = copy_preserve_mtime;
+        }
+
+        if let Some(copy_follow_symlinks) = opts.get("copy_follow_symlinks")? {
+            self.copy_follow_symlinks = copy_follow_symlinks;
+        }
+
+        Ok(())
+    }
+
+    pub fn copy_options(&self) -> CopyOptions {
+        CopyOptions {
+            preserve_mode: self.copy_preserve_mode,
+            preserve_mtime: self.copy_preserve_mtime,
+            follow_symlinks: self.copy_follow_symlinks,
+        }
+    }
+}
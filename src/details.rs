@@ -0,0 +1,195 @@
+use crate::utils::NeoUtils;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    fs::{self, Metadata},
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::Path,
+    time::SystemTime,
+};
+
+static USERS: Lazy<HashMap<u32, String>> = Lazy::new(|| read_id_names("/etc/passwd"));
+static GROUPS: Lazy<HashMap<u32, String>> = Lazy::new(|| read_id_names("/etc/group"));
+
+/// Columns of the long listing of a single entry
+#[derive(Debug, Clone)]
+pub struct EntryDetails {
+    pub mode: String,
+    pub owner: String,
+    pub group: String,
+    pub size: String,
+    pub modified: Option<SystemTime>,
+}
+
+impl EntryDetails {
+    /// Of the item itself, symlinks aren't followed
+    pub fn read(path: &Path) -> Option<Self> {
+        fs::symlink_metadata(path)
+            .ok()
+            .map(|metadata| Self::from_metadata(&metadata))
+    }
+
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let size = if metadata.is_dir() {
+            "-".to_string()
+        } else {
+            NeoUtils::human_size(metadata.len())
+        };
+
+        Self {
+            mode: mode_string(metadata),
+            owner: id_name(&USERS, metadata.uid()),
+            group: id_name(&GROUPS, metadata.gid()),
+            size,
+            modified: metadata.modified().ok(),
+        }
+    }
+
+    pub fn modified(&self, relative: bool, now: SystemTime) -> String {
+        let Some(modified) = self.modified else {
+            return "-".to_string();
+        };
+
+        if relative {
+            return relative_time(modified, now);
+        }
+
+        // 2024-03-01T12:00:00 -> 2024-03-01 12:00
+        let mut absolute = NeoUtils::format_local(modified).replacen('T', " ", 1);
+        absolute.truncate(16);
+        absolute
+    }
+}
+
+/// `ls -l` style type and permissions, e.g. `drwxr-xr-x`
+fn mode_string(metadata: &Metadata) -> String {
+    let file_type = metadata.file_type();
+
+    let kind = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else {
+        '-'
+    };
+
+    let mode = metadata.permissions().mode();
+    let mut text = String::from(kind);
+
+    // Setuid, setgid and sticky replace the execute bit of user, group and other
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = mode >> shift;
+
+        text.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        text.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+
+    text
+}
+
+fn relative_time(time: SystemTime, now: SystemTime) -> String {
+    let secs = now
+        .duration_since(time)
+        .map(|dur| dur.as_secs())
+        .unwrap_or(0);
+
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} min ago", secs / 60),
+        3600..=86_399 => format!("{} h ago", secs / 3600),
+        _ if secs < 86_400 * 30 => units_ago(secs / 86_400, "day"),
+        _ if secs < 86_400 * 365 => units_ago(secs / (86_400 * 30), "month"),
+        _ => units_ago(secs / (86_400 * 365), "year"),
+    }
+}
+
+fn units_ago(count: u64, unit: &str) -> String {
+    if count == 1 {
+        format!("1 {unit} ago")
+    } else {
+        format!("{count} {unit}s ago")
+    }
+}
+
+fn id_name(names: &HashMap<u32, String>, id: u32) -> String {
+    names.get(&id).cloned().unwrap_or_else(|| id.to_string())
+}
+
+/// Names by id of `/etc/passwd` or `/etc/group`, both have them as `name:password:id:...`
+fn read_id_names(path: &str) -> HashMap<u32, String> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+
+            Some((id, name.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mode_string, relative_time};
+    use crate::test_utils::TempDir;
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    pub fn mode_column() {
+        let root = TempDir::new();
+
+        let file = root.join("script.sh");
+        fs::write(&file, "").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o4754)).unwrap();
+
+        let metadata = fs::symlink_metadata(&file).unwrap();
+        assert_eq!("-rwsr-xr--", mode_string(&metadata));
+    }
+
+    #[test]
+    pub fn relative_modified_time() {
+        let now = SystemTime::now();
+        assert_eq!("just now", relative_time(now, now));
+        assert_eq!(
+            "3 h ago",
+            relative_time(now - Duration::from_secs(3 * 3600 + 10), now)
+        );
+        assert_eq!(
+            "2 days ago",
+            relative_time(now - Duration::from_secs(2 * 86_400), now)
+        );
+        assert_eq!(
+            "1 day ago",
+            relative_time(now - Duration::from_secs(86_400 + 10), now)
+        );
+        assert_eq!(
+            "1 month ago",
+            relative_time(now - Duration::from_secs(40 * 86_400), now)
+        );
+        assert_eq!(
+            "1 year ago",
+            relative_time(now - Duration::from_secs(400 * 86_400), now)
+        );
+    }
+}
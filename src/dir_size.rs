@@ -0,0 +1,311 @@
+use crate::state::{AppInstance, AppState};
+use crate::CONTAINER;
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::*;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tokio::task::JoinHandle;
+
+const POLL_INTERVAL_MS: u32 = 100;
+/// Walking `/` would otherwise keep an entry for every directory on the system
+const MAX_CACHED_DIRS: usize = 50_000;
+
+/// The direct entries of a directory at the mtime they were read at
+#[derive(Debug, Clone)]
+struct CachedDir {
+    modified: SystemTime,
+    /// Apparent size of everything but the subdirectories
+    files: u64,
+    subdirs: Vec<OsString>,
+}
+
+/// The mtime of a directory only changes with its own entries, so every subdirectory is still
+/// validated on its own. Files rewritten in place are counted again once their directory changes
+/// or the sizes are turned off, which clears the cache
+static SIZE_CACHE: Lazy<Mutex<HashMap<PathBuf, CachedDir>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Background computation of the directory sizes of a listing
+#[derive(Debug)]
+pub struct DirSizes {
+    dirs: Vec<PathBuf>,
+    sizes: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    cancelled: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+    /// Number of sizes the buffer was last themed with
+    shown: usize,
+}
+
+impl DirSizes {
+    pub fn get(&self, path: &Path) -> Option<u64> {
+        self.sizes.lock().ok()?.get(path).copied()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Apparent size of everything inside the directory, symlinks aren't followed. None if the
+/// computation was cancelled
+pub fn dir_size(path: &Path, cancelled: &AtomicBool) -> Option<u64> {
+    if cancelled.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    // Removed or unreadable directories count as empty
+    let Some(modified) = fs::symlink_metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+    else {
+        return Some(0);
+    };
+
+    let cached = SIZE_CACHE
+        .lock()
+        .ok()
+        .and_then(|cache| cache.get(path).cloned())
+        .filter(|cached| cached.modified == modified);
+
+    let dir = match cached {
+        Some(dir) => dir,
+        None => read_dir_entries(path, modified, cancelled)?,
+    };
+
+    let mut size = dir.files;
+
+    for name in dir.subdirs {
+        size += dir_size(&path.join(name), cancelled)?;
+    }
+
+    Some(size)
+}
+
+fn read_dir_entries(
+    path: &Path,
+    modified: SystemTime,
+    cancelled: &AtomicBool,
+) -> Option<CachedDir> {
+    let mut dir = CachedDir {
+        modified,
+        files: 0,
+        subdirs: vec![],
+    };
+
+    for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            dir.subdirs.push(entry.file_name());
+        } else {
+            dir.files += metadata.len();
+        }
+    }
+
+    if let Ok(mut cache) = SIZE_CACHE.lock() {
+        if cache.len() >= MAX_CACHED_DIRS {
+            cache.clear();
+        }
+
+        cache.insert(path.to_path_buf(), dir.clone());
+    }
+
+    Some(dir)
+}
+
+fn clear_cache() {
+    if let Ok(mut cache) = SIZE_CACHE.lock() {
+        cache.clear();
+    }
+}
+
+impl AppInstance {
+    /// Computes the sizes of the listed directories in the background. A running computation
+    /// for the same directories keeps going, otherwise it's cancelled
+    pub fn start_dir_sizes(&mut self, lua: &Lua) -> LuaResult<()> {
+        let dirs: Vec<_> = self
+            .buf_content
+            .iter()
+            .filter(|item| item.is_dir() && !item.path.is_symlink())
+            .map(|item| item.path.clone())
+            .collect();
+
+        if let Some(running) = &self.dir_sizes {
+            if running.dirs == dirs && !running.cancelled.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+
+            running.cancel();
+        }
+
+        let sizes = Arc::new(Mutex::new(HashMap::new()));
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let task_dirs = dirs.clone();
+        let task_sizes = sizes.clone();
+        let task_cancelled = cancelled.clone();
+
+        let handle = RTM.spawn_blocking(move || {
+            for dir in task_dirs {
+                let Some(size) = dir_size(&dir, &task_cancelled) else {
+                    return;
+                };
+
+                if let Ok(mut sizes) = task_sizes.lock() {
+                    sizes.insert(dir, size);
+                }
+            }
+        });
+
+        self.dir_sizes = Some(DirSizes {
+            dirs,
+            sizes,
+            cancelled: cancelled.clone(),
+            handle,
+            shown: 0,
+        });
+
+        let buf_id = self.buf.id();
+        let poll = lua
+            .create_async_function(move |lua, ()| poll_dir_sizes(lua, buf_id, cancelled.clone()))?;
+
+        NeoApi::delay(lua, POLL_INTERVAL_MS, poll)
+    }
+
+    pub fn cancel_dir_sizes(&mut self) {
+        if let Some(running) = self.dir_sizes.take() {
+            running.cancel();
+        }
+    }
+}
+
+/// Themes the buffer again when sizes arrived, until the computation is done or cancelled
+async fn poll_dir_sizes(lua: &Lua, buf_id: u32, cancelled: Arc<AtomicBool>) -> LuaResult<()> {
+    if cancelled.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let mut instances = CONTAINER.instances.write().await;
+
+    let Some(instance) = instances.get_mut(&buf_id) else {
+        return Ok(());
+    };
+
+    let Some(running) = instance.dir_sizes.as_mut() else {
+        return Ok(());
+    };
+
+    let finished = running.handle.is_finished();
+    let count = running.sizes.lock().map(|sizes| sizes.len()).unwrap_or(0);
+    let arrived = count != running.shown;
+    running.shown = count;
+
+    if arrived {
+        let selection = CONTAINER.selection.read().await;
+        instance.theme_nav_buffer(lua, &selection).await?;
+    }
+
+    if finished {
+        return Ok(());
+    }
+
+    let poll =
+        lua.create_async_function(move |lua, ()| poll_dir_sizes(lua, buf_id, cancelled.clone()))?;
+
+    NeoApi::delay(lua, POLL_INTERVAL_MS, poll)
+}
+
+pub async fn toggle_dir_sizes(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    instance.show_dir_sizes = !instance.show_dir_sizes;
+
+    if instance.show_dir_sizes {
+        return instance.start_dir_sizes(lua);
+    }
+
+    instance.cancel_dir_sizes();
+    clear_cache();
+
+    let selection = CONTAINER.selection.read().await;
+    instance.theme_nav_buffer(lua, &selection).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dir_size, MAX_CACHED_DIRS, SIZE_CACHE};
+    use crate::test_utils::TempDir;
+    use std::{fs, sync::atomic::AtomicBool, thread, time::Duration};
+
+    fn sized_tree() -> TempDir {
+        let root = TempDir::new();
+        fs::create_dir_all(root.join("src/bin")).unwrap();
+
+        fs::write(root.join("README.md"), "x".repeat(100)).unwrap();
+        fs::write(root.join("src/lib.rs"), "x".repeat(20)).unwrap();
+        fs::write(root.join("src/bin/main.rs"), "x".repeat(3)).unwrap();
+
+        root
+    }
+
+    #[test]
+    pub fn recursive_dir_size() {
+        let root = sized_tree();
+        std::os::unix::fs::symlink(root.join("README.md"), root.join("src/link")).unwrap();
+
+        let link_size = fs::symlink_metadata(root.join("src/link")).unwrap().len();
+        let running = AtomicBool::new(false);
+
+        assert_eq!(Some(123 + link_size), dir_size(&root, &running));
+        assert_eq!(Some(3), dir_size(&root.join("src/bin"), &running));
+    }
+
+    #[test]
+    pub fn cancelled_dir_size() {
+        let root = sized_tree();
+
+        assert_eq!(None, dir_size(&root, &AtomicBool::new(true)));
+    }
+
+    #[test]
+    pub fn nested_changes_after_caching() {
+        let root = sized_tree();
+        let running = AtomicBool::new(false);
+        assert_eq!(Some(123), dir_size(&root, &running));
+
+        // Only the mtime of `src/bin` changes
+        thread::sleep(Duration::from_millis(10));
+        fs::write(root.join("src/bin/extra.rs"), "x".repeat(7)).unwrap();
+        assert_eq!(Some(130), dir_size(&root, &running));
+
+        fs::remove_dir_all(root.join("src/bin")).unwrap();
+        assert_eq!(Some(120), dir_size(&root, &running));
+    }
+
+    #[test]
+    pub fn bounded_size_cache() {
+        let root = sized_tree();
+        dir_size(&root, &AtomicBool::new(false));
+
+        let cache = SIZE_CACHE.lock().unwrap();
+        assert!(cache.contains_key(&root.join("src/bin")));
+        assert!(cache.len() <= MAX_CACHED_DIRS);
+    }
+}
@@ -0,0 +1,644 @@
+use crate::error::keymap_fn;
+use crate::fs_ops::{move_item, Progress};
+use crate::job::{JobKind, JobSummary, PreviewLine};
+use crate::journal::FsOperation;
+use crate::popup::preview_operations_popup;
+use crate::state::{delete_item, AppInstance, AppState, SelectionData, NAV_KEYMAPS};
+use crate::utils::NeoUtils;
+use crate::CONTAINER;
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::*;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const DISCARD_KEYMAP: &str = "<C-q>";
+
+/// Listing of a directory before and after it has been edited as text
+#[derive(Debug, Clone)]
+pub struct DirEdit {
+    pub original: Vec<String>,
+    pub edited: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditOperation {
+    Create {
+        path: PathBuf,
+        is_dir: bool,
+    },
+    /// Rename inside a directory or a move to another one
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Trash(PathBuf),
+}
+
+/// Filesystem operations that turn the listings on disk into the edited ones, in the order they
+/// need to be applied
+#[derive(Debug, Clone, Default)]
+pub struct EditPlan {
+    pub operations: Vec<EditOperation>,
+}
+
+struct Change<'a> {
+    dir: &'a Path,
+    name: &'a str,
+    hunk: usize,
+}
+
+impl EditPlan {
+    pub fn new(edits: &HashMap<PathBuf, DirEdit>) -> Result<Self, String> {
+        let mut dirs: Vec<_> = edits.iter().collect();
+        dirs.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut removed = vec![];
+        let mut added = vec![];
+        let mut hunk_offset = 0;
+
+        for (dir, edit) in dirs {
+            let mut names = HashSet::new();
+
+            for name in edit.edited.iter() {
+                // Only a single trailing slash, anything else would reach outside the directory
+                let base = name.strip_suffix('/').unwrap_or(name);
+
+                if base.contains('/') || matches!(base, "" | "." | "..") {
+                    return Err(format!("{name} is not a valid name"));
+                }
+
+                if !names.insert(base) {
+                    return Err(format!("{name} is listed twice in {}", dir.display()));
+                }
+            }
+
+            let hunks = diff_hunks(&edit.original, &edit.edited);
+
+            for (i, (hunk_removed, hunk_added)) in hunks.iter().enumerate() {
+                let hunk = hunk_offset + i;
+                let change = |name| Change { dir, name, hunk };
+
+                removed.extend(hunk_removed.iter().copied().map(change));
+                added.extend(hunk_added.iter().copied().map(change));
+            }
+
+            hunk_offset += hunks.len();
+        }
+
+        // Lines that only changed place inside the listing
+        removed.retain(|from: &Change| {
+            let kept = added
+                .iter()
+                .position(|to| to.dir == from.dir && to.name == from.name);
+
+            kept.map(|idx| added.remove(idx)).is_none()
+        });
+
+        let mut moves = vec![];
+
+        // Lines cut in one directory and pasted into another
+        removed.retain(|from| {
+            let pasted = added.iter().position(|to| to.name == from.name);
+
+            match pasted {
+                Some(idx) => {
+                    let to = added.remove(idx);
+                    moves.push((
+                        NeoUtils::item_path(from.dir, from.name),
+                        NeoUtils::item_path(to.dir, to.name),
+                    ));
+                    false
+                }
+                None => true,
+            }
+        });
+
+        // Lines replaced in place are renames, as long as they stay a file or a directory
+        removed.retain(|from| {
+            let renamed = added.iter().position(|to| {
+                to.hunk == from.hunk && to.name.ends_with('/') == from.name.ends_with('/')
+            });
+
+            match renamed {
+                Some(idx) => {
+                    let to = added.remove(idx);
+                    moves.push((
+                        NeoUtils::item_path(from.dir, from.name),
+                        NeoUtils::item_path(to.dir, to.name),
+                    ));
+                    false
+                }
+                None => true,
+            }
+        });
+
+        let mut operations: Vec<_> = removed
+            .iter()
+            .map(|change| EditOperation::Trash(NeoUtils::item_path(change.dir, change.name)))
+            .collect();
+
+        let freed: HashSet<PathBuf> = removed
+            .iter()
+            .map(|change| NeoUtils::item_path(change.dir, change.name))
+            .collect();
+
+        for (from, to) in moves {
+            if !is_free(&to, &freed) {
+                return Err(format!("{} already exists", to.display()));
+            }
+
+            operations.push(EditOperation::Move { from, to });
+        }
+
+        for change in added {
+            let path = NeoUtils::item_path(change.dir, change.name);
+
+            if !is_free(&path, &freed) {
+                return Err(format!("{} already exists", path.display()));
+            }
+
+            operations.push(EditOperation::Create {
+                path,
+                is_dir: change.name.ends_with('/'),
+            });
+        }
+
+        Ok(Self { operations })
+    }
+
+    pub fn paths(&self) -> Vec<&Path> {
+        self.operations
+            .iter()
+            .flat_map(|operation| match operation {
+                EditOperation::Create { path, .. } | EditOperation::Trash(path) => {
+                    vec![path.as_path()]
+                }
+                EditOperation::Move { from, to } => vec![from.as_path(), to.as_path()],
+            })
+            .collect()
+    }
+
+    pub fn preview(&self) -> Vec<PreviewLine> {
+        let mut lines = vec![PreviewLine {
+            text: format!("Apply {} edit(s)", self.operations.len()),
+            highlight: Some("Title"),
+        }];
+
+        for operation in self.operations.iter() {
+            let (text, highlight) = match operation {
+                EditOperation::Create { path, .. } => {
+                    (format!("  create {}", path.display()), None)
+                }
+                EditOperation::Move { from, to } if from.parent() == to.parent() => (
+                    format!(
+                        "  rename {} -> {}",
+                        from.display(),
+                        to.file_name().unwrap_or_default().to_string_lossy()
+                    ),
+                    None,
+                ),
+                EditOperation::Move { from, to } => (
+                    format!("  move {} -> {}", from.display(), to.display()),
+                    None,
+                ),
+                EditOperation::Trash(path) => {
+                    (format!("  trash {}", path.display()), Some("WarningMsg"))
+                }
+            };
+
+            lines.push(PreviewLine { text, highlight });
+        }
+
+        lines
+    }
+
+    /// Applies the operations in order, stops at the first failure because later ones can depend
+    /// on it
+    pub fn execute(self, progress: &Progress) -> JobSummary {
+        let total = self.operations.len();
+        progress.settle(0, 0);
+        progress
+            .files_total
+            .store(total as u64, std::sync::atomic::Ordering::Relaxed);
+
+        let mut summary = JobSummary::default();
+        let mut applied = 0;
+
+        for operation in self.operations {
+            if progress.is_cancelled() {
+                break;
+            }
+
+            let result = match &operation {
+                EditOperation::Create { path, is_dir } => create_item(path, *is_dir),
+                EditOperation::Move { from, to } => move_item(from, to, progress)
+                    .map(|_| FsOperation::Rename {
+                        from: from.clone(),
+                        to: to.clone(),
+                    })
+                    .map(Some),
+                EditOperation::Trash(path) => delete_item(path, false, progress),
+            };
+
+            match result {
+                Ok(journaled) => {
+                    summary.operations.extend(journaled);
+                    applied += 1;
+                    progress.settle(applied, 0);
+                }
+                Err(err) => {
+                    summary.failed = true;
+                    summary
+                        .messages
+                        .push(format!("Failed {operation:?}: {err}"));
+                    break;
+                }
+            }
+        }
+
+        let mut header = format!("Applied: {applied}, of: {total}");
+
+        if progress.is_cancelled() {
+            header.push_str(" (cancelled)");
+        }
+
+        summary.messages.insert(0, header);
+        summary
+    }
+}
+
+fn is_free(path: &Path, freed: &HashSet<PathBuf>) -> bool {
+    freed.contains(path) || path.symlink_metadata().is_err()
+}
+
+/// Names can't contain a slash, so the parent is always the listed directory
+fn create_item(path: &Path, is_dir: bool) -> io::Result<Option<FsOperation>> {
+    if is_dir {
+        fs::create_dir(path)?;
+    } else {
+        fs::File::create_new(path)?;
+    }
+
+    Ok(Some(FsOperation::Create {
+        path: path.to_path_buf(),
+        is_dir,
+    }))
+}
+
+/// Groups the changed lines into hunks of removed and added lines, lines that changed next to each
+/// other end up in the same hunk
+fn diff_hunks<'a>(
+    original: &'a [String],
+    edited: &'a [String],
+) -> Vec<(Vec<&'a str>, Vec<&'a str>)> {
+    let prefix = original
+        .iter()
+        .zip(edited)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let suffix = original[prefix..]
+        .iter()
+        .rev()
+        .zip(edited[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let a = &original[prefix..original.len() - suffix];
+    let b = &edited[prefix..edited.len() - suffix];
+
+    // Longest common subsequence of the part in between
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut hunks = vec![];
+    let mut hunk: (Vec<&str>, Vec<&str>) = (vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            if !hunk.0.is_empty() || !hunk.1.is_empty() {
+                hunks.push(std::mem::take(&mut hunk));
+            }
+
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            hunk.0.push(&a[i]);
+            i += 1;
+        } else {
+            hunk.1.push(&b[j]);
+            j += 1;
+        }
+    }
+
+    if !hunk.0.is_empty() || !hunk.1.is_empty() {
+        hunks.push(hunk);
+    }
+
+    hunks
+}
+
+impl AppInstance {
+    pub fn is_editing(&self) -> bool {
+        self.edits.is_some()
+    }
+
+    /// Keeps the edited lines of the current directory, so they survive navigating elsewhere
+    fn stash_edits(&mut self, lua: &Lua) -> LuaResult<()> {
+        let Some(edits) = self.edits.as_mut() else {
+            return Ok(());
+        };
+
+        let edited: Vec<String> = self
+            .buf
+            .get_lines(lua, 0, -1, false)?
+            .into_iter()
+            .filter(|line| !line.trim().is_empty())
+            // Stray spaces at the end of a line are never part of the name
+            .map(|line| line.trim_end().to_string())
+            .collect();
+
+        let original: Vec<String> = self
+            .buf_content
+            .iter()
+            .map(|item| item.name.clone())
+            .collect();
+
+        if edited == original {
+            edits.remove(&self.cwd);
+        } else {
+            edits.insert(self.cwd.clone(), DirEdit { original, edited });
+        }
+
+        Ok(())
+    }
+
+    async fn enter_edit_mode(&mut self, lua: &Lua, selection: &SelectionData) -> LuaResult<()> {
+        for lhs in NAV_KEYMAPS {
+            del_keymap(lua, self.buf, lhs)?;
+        }
+
+        let km_opts = self.buf.keymap_opts(true);
+
+        let open_dir = keymap_fn(lua, open_dir_while_editing)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "<Cr>", open_dir.clone(), km_opts)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "<Right>", open_dir, km_opts)?;
+
+        let nav_to_parent = keymap_fn(lua, navigate_to_parent_while_editing)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "<Left>", nav_to_parent, km_opts)?;
+
+        let discard = keymap_fn(lua, discard_edits)?;
+        NeoApi::set_keymap(lua, Mode::Normal, DISCARD_KEYMAP, discard, km_opts)?;
+
+        self.edits = Some(HashMap::new());
+        self.buf.set_option_value(lua, "buftype", "acwrite")?;
+        // Without a name `:w` stops at E32 before `BufWriteCmd` fires
+        set_buf_name(
+            lua,
+            self.buf,
+            &format!("traveller://{}/edit", self.buf.id()),
+        )?;
+        self.reload_buffer(lua, selection).await
+    }
+
+    pub async fn leave_edit_mode(&mut self, lua: &Lua, selection: &SelectionData) -> LuaResult<()> {
+        if !self.is_editing() {
+            return Ok(());
+        }
+
+        del_keymap(lua, self.buf, DISCARD_KEYMAP)?;
+        self.add_keymaps(lua)?;
+
+        self.edits = None;
+        self.buf.set_option_value(lua, "buftype", "nofile")?;
+        self.buf.set_option_value(lua, "modified", false)?;
+
+        let buf_id = self.buf.id();
+        NeoApi::set_cmd_file(lua, format!("Traveller ({buf_id}) [{}]", self.sort))?;
+
+        self.reload_buffer(lua, selection).await
+    }
+}
+
+fn set_buf_name(lua: &Lua, buf: NeoBuffer, name: &str) -> LuaResult<()> {
+    let api: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("api")?;
+    let set_name: LuaFunction = api.get("nvim_buf_set_name")?;
+
+    set_name.call((buf.id(), name))
+}
+
+fn del_keymap(lua: &Lua, buf: NeoBuffer, lhs: &str) -> LuaResult<()> {
+    let keymap: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("keymap")?;
+    let del: LuaFunction = keymap.get("del")?;
+
+    let opts = lua.create_table()?;
+    opts.set("buffer", buf.id())?;
+
+    del.call(("n", lhs, opts))
+}
+
+pub async fn edit_mode(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    let selection = CONTAINER.selection.read().await;
+    instance.enter_edit_mode(lua, &selection).await?;
+
+    NeoApi::notify(
+        lua,
+        &format!("Edit mode: :w applies the changes, {DISCARD_KEYMAP} discards them"),
+    )
+}
+
+async fn discard_edits(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    let selection = CONTAINER.selection.read().await;
+    instance.leave_edit_mode(lua, &selection).await
+}
+
+/// Every instance leaves edit mode, used once its edits have been planned
+pub async fn leave_edit_modes(lua: &Lua) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let selection = CONTAINER.selection.read().await;
+
+    for instance in instances.values_mut() {
+        instance.leave_edit_mode(lua, &selection).await?;
+    }
+
+    Ok(())
+}
+
+async fn open_dir_while_editing(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    let item = NeoApi::get_current_line(lua)?.trim_end().to_string();
+
+    // Files can't be opened, leaving the buffer would throw the edits away
+    if !item.ends_with('/') || !instance.cwd.join(&item).is_dir() {
+        return Ok(());
+    }
+
+    instance.stash_edits(lua)?;
+    instance.update_history(item.clone()).await;
+    instance.cwd.push(&item);
+
+    let selection = CONTAINER.selection.read().await;
+    instance.set_buffer_content(lua, &selection).await
+}
+
+async fn navigate_to_parent_while_editing(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    let Some(item) = instance.cwd.file_name() else {
+        return Ok(());
+    };
+
+    let item = format!("{}/", NeoUtils::escape_name(item));
+
+    instance.stash_edits(lua)?;
+    instance.cwd.pop();
+    instance.update_history(item).await;
+
+    let selection = CONTAINER.selection.read().await;
+    instance.set_buffer_content(lua, &selection).await
+}
+
+/// `:w` on a traveller buffer plans the edits of every instance and asks for confirmation
+pub async fn buf_write_callback(lua: &Lua, _: AutoCmdCbEvent) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let mut edits = HashMap::new();
+
+    for instance in instances.values_mut() {
+        instance.stash_edits(lua)?;
+
+        for (dir, edit) in instance.edits.iter().flatten() {
+            if edits.insert(dir.clone(), edit.clone()).is_some() {
+                let msg = format!("Edit refused: {} is edited in two windows", dir.display());
+                return NeoApi::notify(lua, &msg);
+            }
+        }
+    }
+
+    drop(instances);
+
+    match EditPlan::new(&edits) {
+        Ok(plan) if plan.operations.is_empty() => leave_edit_modes(lua).await,
+        Ok(plan) => preview_operations_popup(lua, JobKind::Edit(plan)).await,
+        Err(err) => NeoApi::notify(lua, &format!("Edit refused: {err}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DirEdit, EditOperation, EditPlan};
+    use crate::test_utils::TempDir;
+    use std::{collections::HashMap, fs, path::PathBuf};
+
+    fn lines(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn edit(dir: PathBuf, original: &[&str], edited: &[&str]) -> HashMap<PathBuf, DirEdit> {
+        HashMap::from([(
+            dir,
+            DirEdit {
+                original: lines(original),
+                edited: lines(edited),
+            },
+        )])
+    }
+
+    #[test]
+    pub fn plan_edits() {
+        let root = TempDir::new();
+        let (one, two) = (root.join("one"), root.join("two"));
+        fs::create_dir_all(one.join("dir")).unwrap();
+        fs::create_dir_all(&two).unwrap();
+
+        for name in ["a.txt", "b.txt", "c.txt", "moved.txt"] {
+            fs::write(one.join(name), "").unwrap();
+        }
+
+        let mut edits = edit(
+            one.clone(),
+            &["dir/", "a.txt", "b.txt", "c.txt", "moved.txt"],
+            &["dir/", "renamed.txt", "c.txt", "new/"],
+        );
+        edits.extend(edit(two.clone(), &[], &["moved.txt"]));
+
+        let plan = EditPlan::new(&edits).unwrap();
+
+        assert_eq!(
+            vec![
+                EditOperation::Trash(one.join("b.txt")),
+                EditOperation::Move {
+                    from: one.join("moved.txt"),
+                    to: two.join("moved.txt"),
+                },
+                EditOperation::Move {
+                    from: one.join("a.txt"),
+                    to: one.join("renamed.txt"),
+                },
+                EditOperation::Create {
+                    path: one.join("new"),
+                    is_dir: true,
+                },
+            ],
+            plan.operations
+        );
+    }
+
+    #[test]
+    pub fn reordered_lines() {
+        let root = TempDir::new();
+        let edits = edit(root.to_path_buf(), &["a.txt", "b.txt"], &["b.txt", "a.txt"]);
+
+        assert!(EditPlan::new(&edits).unwrap().operations.is_empty());
+    }
+
+    #[test]
+    pub fn existing_target() {
+        let root = TempDir::new();
+        fs::write(root.join("c.txt"), "").unwrap();
+
+        let edits = edit(root.to_path_buf(), &["a.txt"], &["c.txt"]);
+        assert!(EditPlan::new(&edits).is_err());
+    }
+
+    #[test]
+    pub fn invalid_names() {
+        let root = TempDir::new();
+
+        for name in [
+            "../x.txt",
+            "sub/../../x.txt",
+            "/tmp/x.txt",
+            "a//",
+            "sub/x.txt",
+            "./",
+        ] {
+            let edits = edit(root.to_path_buf(), &["a.txt"], &[name]);
+            assert!(EditPlan::new(&edits).is_err(), "{name}");
+        }
+
+        let edits = edit(root.to_path_buf(), &["a.txt"], &["sub/"]);
+        assert!(EditPlan::new(&edits).is_ok());
+    }
+}
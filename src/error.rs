@@ -0,0 +1,78 @@
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::NeoApi;
+use std::{
+    fmt,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum TravellerError {
+    /// The buffer has no navigation instance (anymore)
+    NoInstance(u32),
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Git(String),
+    InvalidInput(String),
+}
+
+pub type TravellerResult<T> = Result<T, TravellerError>;
+
+impl TravellerError {
+    /// Attaches the path to an IO error, to be used with `map_err`
+    pub fn io(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |source| Self::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for TravellerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoInstance(buf_id) => write!(f, "No traveller instance for buffer {buf_id}"),
+            Self::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Self::Git(msg) => write!(f, "Git: {msg}"),
+            Self::InvalidInput(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for TravellerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<TravellerError> for LuaError {
+    fn from(err: TravellerError) -> Self {
+        LuaError::external(err)
+    }
+}
+
+/// Creates a keymap callback that reports its error with a notification, instead of handing it
+/// to Neovim as a Lua error with a traceback
+pub fn keymap_fn<'lua, F, FR>(lua: &'lua Lua, func: F) -> LuaResult<LuaFunction<'lua>>
+where
+    F: Fn(&'lua Lua, ()) -> FR + 'static,
+    FR: Future<Output = LuaResult<()>> + 'lua,
+{
+    lua.create_async_function(move |lua, ()| {
+        let result = func(lua, ());
+
+        async move {
+            if let Err(err) = result.await {
+                NeoApi::notify(lua, &err)?;
+            }
+
+            Ok(())
+        }
+    })
+}
@@ -0,0 +1,179 @@
+use crate::icons::set_hl;
+use neo_api_rs::mlua::prelude::*;
+use std::{
+    fs::{self, Metadata},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+const SETUID: u32 = 0o4000;
+const SETGID: u32 = 0o2000;
+
+/// What the item is, symlinks are followed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileKind {
+    #[default]
+    File,
+    Dir,
+    /// Any of the execute bits is set
+    Executable,
+    Setuid,
+    Setgid,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl FileKind {
+    /// Regular file when the metadata can't be read, e.g. for broken links
+    pub fn read(path: &Path) -> Self {
+        fs::metadata(path)
+            .map(|metadata| Self::from_metadata(&metadata))
+            .unwrap_or_default()
+    }
+
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let mode = metadata.permissions().mode();
+
+        if file_type.is_dir() {
+            Self::Dir
+        } else if file_type.is_fifo() {
+            Self::Fifo
+        } else if file_type.is_socket() {
+            Self::Socket
+        } else if file_type.is_block_device() {
+            Self::BlockDevice
+        } else if file_type.is_char_device() {
+            Self::CharDevice
+        } else if mode & SETUID != 0 {
+            Self::Setuid
+        } else if mode & SETGID != 0 {
+            Self::Setgid
+        } else if mode & 0o111 != 0 {
+            Self::Executable
+        } else {
+            Self::File
+        }
+    }
+
+    /// Appended to the name like `ls -F` does, directories already end with a slash
+    pub fn indicator(&self) -> Option<&'static str> {
+        match self {
+            Self::Executable | Self::Setuid | Self::Setgid => Some("*"),
+            Self::Fifo => Some("|"),
+            Self::Socket => Some("="),
+            Self::File | Self::Dir | Self::BlockDevice | Self::CharDevice => None,
+        }
+    }
+
+    pub fn hl_group(&self) -> Option<&'static str> {
+        match self {
+            Self::File | Self::Dir => None,
+            Self::Executable => Some("TravellerExecutable"),
+            Self::Setuid => Some("TravellerSetuid"),
+            Self::Setgid => Some("TravellerSetgid"),
+            Self::Fifo => Some("TravellerFifo"),
+            Self::Socket => Some("TravellerSocket"),
+            Self::BlockDevice | Self::CharDevice => Some("TravellerDevice"),
+        }
+    }
+
+    /// Opening these in a buffer would block on reading them, or read nothing at all
+    pub fn is_special(&self) -> bool {
+        matches!(
+            self,
+            Self::Fifo | Self::Socket | Self::BlockDevice | Self::CharDevice
+        )
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Dir => "directory",
+            Self::Executable => "executable",
+            Self::Setuid => "setuid executable",
+            Self::Setgid => "setgid executable",
+            Self::Fifo => "named pipe",
+            Self::Socket => "socket",
+            Self::BlockDevice => "block device",
+            Self::CharDevice => "character device",
+        }
+    }
+}
+
+/// Links the groups with `default`, so colour schemes and users can override them
+pub fn define_kind_highlights(lua: &Lua) -> LuaResult<()> {
+    for (name, link) in [
+        ("TravellerExecutable", "DiagnosticOk"),
+        ("TravellerSetuid", "ErrorMsg"),
+        ("TravellerSetgid", "WarningMsg"),
+        ("TravellerFifo", "Constant"),
+        ("TravellerSocket", "Identifier"),
+        ("TravellerDevice", "PreProc"),
+    ] {
+        let opts = lua.create_table()?;
+        opts.set("link", link)?;
+        opts.set("default", true)?;
+
+        set_hl(lua, name, opts)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::FileKind;
+    use crate::test_utils::TempDir;
+    use std::{
+        fs,
+        os::unix::{fs::PermissionsExt, net::UnixListener},
+        path::Path,
+        process::Command,
+    };
+
+    fn with_mode(root: &Path, name: &str, mode: u32) -> FileKind {
+        let path = root.join(name);
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+
+        FileKind::read(&path)
+    }
+
+    #[test]
+    pub fn regular_file_kinds() {
+        let root = TempDir::new();
+
+        assert_eq!(FileKind::Dir, FileKind::read(&root));
+        assert_eq!(FileKind::File, with_mode(&root, "notes.txt", 0o644));
+        assert_eq!(FileKind::Executable, with_mode(&root, "run.sh", 0o755));
+        assert_eq!(FileKind::Setuid, with_mode(&root, "setuid", 0o4755));
+        assert_eq!(FileKind::File, FileKind::read(&root.join("missing")));
+
+        assert_eq!(Some("*"), FileKind::Executable.indicator());
+        assert!(!FileKind::Executable.is_special());
+    }
+
+    #[test]
+    pub fn special_file_kinds() {
+        let root = TempDir::new();
+
+        let _listener = UnixListener::bind(root.join("socket")).unwrap();
+        Command::new("mkfifo")
+            .arg(root.join("pipe"))
+            .status()
+            .unwrap();
+
+        let kind = |name: &str| FileKind::read(&root.join(name));
+
+        assert_eq!(FileKind::Fifo, kind("pipe"));
+        assert_eq!(FileKind::Socket, kind("socket"));
+        assert_eq!(FileKind::CharDevice, FileKind::read(Path::new("/dev/null")));
+
+        assert_eq!(Some("|"), kind("pipe").indicator());
+        assert_eq!(Some("="), kind("socket").indicator());
+        assert!(kind("pipe").is_special());
+    }
+}
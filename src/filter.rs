@@ -0,0 +1,244 @@
+use crate::error::keymap_fn;
+use crate::state::{AppState, NavItem};
+use crate::CONTAINER;
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::*;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Narrows the listing of the directory it was typed in
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub dir: PathBuf,
+    pub query: String,
+}
+
+impl Filter {
+    /// Keeps the matching items, and in the tree view the directories they're nested in
+    pub fn apply(&self, items: Vec<NavItem>) -> Vec<NavItem> {
+        let mut kept = vec![];
+        // Rows above with a lower depth than the last kept row are its ancestors
+        let mut ancestor_depth = 0;
+
+        for item in items.into_iter().rev() {
+            if item.depth < ancestor_depth || match_positions(&self.query, &item.name).is_some() {
+                ancestor_depth = item.depth;
+                kept.push(item);
+            }
+        }
+
+        kept.reverse();
+        kept
+    }
+}
+
+/// Byte ranges of the matched characters in the name. The query matches fuzzy, or as a
+/// substring when it starts with `'`. Case is ignored unless the query has capitals
+pub fn match_positions(query: &str, name: &str) -> Option<Vec<Range<usize>>> {
+    let (query, substring) = match query.strip_prefix('\'') {
+        Some(query) => (query, true),
+        None => (query, false),
+    };
+
+    let ignore_case = !query.chars().any(char::is_uppercase);
+    let chars_eq = |a: char, b: char| {
+        if ignore_case {
+            a.to_lowercase().eq(b.to_lowercase())
+        } else {
+            a == b
+        }
+    };
+
+    let query: Vec<char> = query.chars().collect();
+    let name: Vec<(usize, char)> = name.char_indices().collect();
+    let range = |(start, c): (usize, char)| start..start + c.len_utf8();
+
+    if substring {
+        if query.is_empty() {
+            return Some(vec![]);
+        }
+
+        return name.windows(query.len()).find_map(|window| {
+            let found = window
+                .iter()
+                .zip(query.iter())
+                .all(|((_, a), b)| chars_eq(*a, *b));
+
+            found.then(|| window.iter().copied().map(range).collect())
+        });
+    }
+
+    let mut positions = vec![];
+    let mut name_chars = name.into_iter();
+
+    for query_char in query {
+        let found = name_chars.find(|(_, c)| chars_eq(*c, query_char))?;
+        positions.push(range(found));
+    }
+
+    Some(positions)
+}
+
+/// One line prompt that narrows the listing while typing. Enter keeps the filter, escape
+/// removes it again
+pub async fn filter_popup(lua: &Lua, _: ()) -> LuaResult<()> {
+    let instances = CONTAINER.instances.read().await;
+    let instance = AppState::active_instance(&instances)?;
+
+    let query = instance
+        .filter
+        .as_ref()
+        .map(|filter| filter.query.clone())
+        .unwrap_or_default();
+
+    drop(instances);
+
+    let popup_buf = NeoBuffer::create(lua, false, true)?;
+    popup_buf.set_lines(lua, 0, -1, false, &[query.as_str()])?;
+
+    let popup_win = NeoPopup::open_win(
+        lua,
+        &popup_buf,
+        true,
+        WinOptions {
+            relative: PopupRelative::Editor,
+            width: Some(PopupSize::Percentage(0.6)),
+            height: Some(PopupSize::Fixed(1)),
+            row: Some(PopupSize::Percentage(0.1)),
+            col: Some(PopupSize::Percentage(0.2)),
+            style: Some(PopupStyle::Minimal),
+            border: PopupBorder::Rounded,
+            title: Some(TextType::Tuples(vec![HLText::new(
+                " Filter: fuzzy or 'substring, keep: (enter), clear: (escape) ",
+                "Question",
+            )])),
+            title_pos: PopupAlign::Right,
+            ..Default::default()
+        },
+    )?;
+
+    popup_win.set_cursor(lua, WinCursor::from_zero_indexed(0, query.len() as u32))?;
+    NeoApi::set_insert_mode(lua, true)?;
+
+    let update_filter = lua.create_async_function(move |lua: &Lua, ()| async move {
+        let query = popup_buf.get_lines(lua, 0, 1, false)?.join("");
+        set_filter(lua, query).await
+    })?;
+
+    NeoApi::create_autocmd(
+        lua,
+        &[AutoCmdEvent::TextChanged, AutoCmdEvent::TextChangedI],
+        AutoCmdOpts {
+            buffer: Some(popup_buf.id()),
+            callback: update_filter,
+            desc: None,
+            group: None,
+            pattern: vec![],
+            once: false,
+        },
+    )?;
+
+    let stop_insert = lua.create_function(|lua: &Lua, ()| NeoApi::set_insert_mode(lua, false))?;
+
+    NeoApi::create_autocmd(
+        lua,
+        &[AutoCmdEvent::BufLeave],
+        AutoCmdOpts {
+            buffer: Some(popup_buf.id()),
+            callback: stop_insert,
+            desc: None,
+            group: None,
+            pattern: vec![],
+            once: true,
+        },
+    )?;
+
+    let keep_filter = lua.create_function(move |lua: &Lua, ()| popup_win.close(lua, true))?;
+    popup_buf.set_keymap(lua, Mode::Normal, "<Cr>", keep_filter.clone())?;
+    popup_buf.set_keymap(lua, Mode::Insert, "<Cr>", keep_filter)?;
+
+    let clear_filter = keymap_fn(lua, move |lua: &Lua, ()| async move {
+        popup_win.close(lua, true)?;
+        set_filter(lua, String::new()).await
+    })?;
+
+    popup_buf.set_keymap(lua, Mode::Normal, "<Esc>", clear_filter)
+}
+
+async fn set_filter(lua: &Lua, query: String) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+    let selection = CONTAINER.selection.read().await;
+
+    if query.is_empty() {
+        // The item under the cursor stays under it once the whole listing is back
+        if let Some(filter) = instance.filter.take() {
+            let row = instance.win.get_cursor(lua)?.row_zero_indexed() as usize;
+
+            if let Some(item) = instance.buf_content.get(row) {
+                let item = item.relative_name(&filter.dir);
+                instance.update_history(item).await;
+            }
+        }
+
+        return instance.set_buffer_content(lua, &selection).await;
+    }
+
+    instance.filter = Some(Filter {
+        dir: instance.cwd.clone(),
+        query,
+    });
+
+    instance.reload_buffer(lua, &selection).await?;
+
+    if !instance.buf_content.is_empty() {
+        instance
+            .win
+            .set_cursor(lua, WinCursor::from_zero_indexed(0, 0))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{match_positions, Filter};
+    use crate::state::NavItem;
+    use std::path::Path;
+
+    #[test]
+    pub fn filter_listing() {
+        assert_eq!(
+            Some(vec![0..1, 5..6, 6..7]),
+            match_positions("mrs", "main.rs")
+        );
+        assert_eq!(Some(vec![5..6, 6..7]), match_positions("'rs", "main.rs"));
+        assert_eq!(None, match_positions("'mrs", "main.rs"));
+        assert_eq!(None, match_positions("Main", "main.rs"));
+        assert_eq!(Some(vec![0..2, 2..3]), match_positions("él", "Élan"));
+
+        let cwd = Path::new("/project");
+        let items = vec![
+            NavItem::new(cwd, "docs/".to_string(), 0),
+            NavItem::new(&cwd.join("docs"), "guide.md".to_string(), 1),
+            NavItem::new(cwd, "src/".to_string(), 0),
+            NavItem::new(&cwd.join("src"), "bin/".to_string(), 1),
+            NavItem::new(&cwd.join("src/bin"), "main.rs".to_string(), 2),
+            NavItem::new(&cwd.join("src"), "lib.rs".to_string(), 1),
+            NavItem::new(cwd, "Cargo.toml".to_string(), 0),
+        ];
+
+        let filter = Filter {
+            dir: cwd.to_path_buf(),
+            query: "'.rs".to_string(),
+        };
+
+        let names: Vec<_> = filter
+            .apply(items)
+            .into_iter()
+            .map(|item| item.name)
+            .collect();
+
+        assert_eq!(vec!["src/", "bin/", "main.rs", "lib.rs"], names);
+    }
+}
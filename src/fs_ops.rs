@@ -0,0 +1,581 @@
+use std::{
+    ffi::CString,
+    fmt, fs,
+    io::{self, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, FileTypeExt, MetadataExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Shared between a background job and the UI that reports on it
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub files_done: AtomicU64,
+    pub files_total: AtomicU64,
+    pub bytes_done: AtomicU64,
+    pub bytes_total: AtomicU64,
+    pub cancelled: AtomicBool,
+}
+
+impl Progress {
+    /// Adds the files and bytes of the tree to the totals, and returns them
+    pub fn scan(&self, path: &Path) -> (u64, u64) {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return (0, 0);
+        };
+
+        if !metadata.is_dir() {
+            self.files_total.fetch_add(1, Ordering::Relaxed);
+            self.bytes_total
+                .fetch_add(metadata.len(), Ordering::Relaxed);
+
+            return (1, metadata.len());
+        }
+
+        let mut total = (0, 0);
+
+        for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+            let (files, bytes) = self.scan(&entry.path());
+            total = (total.0 + files, total.1 + bytes);
+        }
+
+        total
+    }
+
+    /// Sets the done counters, used once an item is finished however it was handled
+    pub fn settle(&self, files_done: u64, bytes_done: u64) {
+        self.files_done.store(files_done, Ordering::Relaxed);
+        self.bytes_done.store(bytes_done, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn check_cancelled(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// What a list of trees adds up to
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TreeTotals {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,
+}
+
+impl TreeTotals {
+    /// None if it was cancelled before every tree was walked
+    pub fn count(paths: &[PathBuf], cancelled: &AtomicBool) -> Option<Self> {
+        let mut totals = Self::default();
+
+        for path in paths {
+            totals.add(path, cancelled)?;
+        }
+
+        Some(totals)
+    }
+
+    fn add(&mut self, path: &Path, cancelled: &AtomicBool) -> Option<()> {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Some(());
+        };
+
+        if metadata.is_dir() {
+            self.dirs += 1;
+
+            for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+                self.add(&entry.path(), cancelled)?;
+            }
+        } else {
+            self.files += 1;
+            self.bytes += metadata.len();
+        }
+
+        Some(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CopyOptions {
+    /// Keep the permission bits of the source
+    pub preserve_mode: bool,
+    /// Keep the modification times of the source
+    pub preserve_mtime: bool,
+    /// Copy what symlinks point to instead of the links themselves
+    pub follow_symlinks: bool,
+}
+
+/// State of a single `copy_recursive`
+struct TreeCopy<'a> {
+    options: CopyOptions,
+    progress: &'a Progress,
+    /// Device and inode of the directories being copied, followed symlinks can lead back to them
+    ancestors: Vec<(u64, u64)>,
+    skipped: Vec<PathBuf>,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            preserve_mode: true,
+            preserve_mtime: true,
+            follow_symlinks: false,
+        }
+    }
+}
+
+/// Copy failure of a single path inside the tree
+#[derive(Debug)]
+pub struct CopyError {
+    pub path: PathBuf,
+    pub source: io::Error,
+}
+
+impl CopyError {
+    fn at(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |source| Self {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.source)
+    }
+}
+
+impl std::error::Error for CopyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<CopyError> for io::Error {
+    fn from(err: CopyError) -> Self {
+        io::Error::new(err.source.kind(), err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveOutcome {
+    Renamed,
+    /// Source lived on another filesystem, so it has been copied and removed afterwards
+    CopiedAcross,
+}
+
+/// Moves the item, falling back to copy, verify and delete when `rename` can't cross filesystems
+pub fn move_item(source: &Path, target: &Path, progress: &Progress) -> io::Result<MoveOutcome> {
+    match fs::rename(source, target) {
+        Ok(()) => return Ok(MoveOutcome::Renamed),
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {}
+        Err(err) => return Err(err),
+    }
+
+    let copied = copy_recursive(source, target, CopyOptions::default(), progress)
+        .map_err(io::Error::from)
+        .and_then(|skipped| match skipped.first() {
+            // The source is removed afterwards, so nothing may be left out
+            Some(path) => Err(io::Error::other(format!(
+                "{} can't be moved to another filesystem",
+                path.display()
+            ))),
+            None => verify_copy(source, target),
+        });
+
+    if let Err(err) = copied {
+        let _ = remove_item(target, &Progress::default());
+        return Err(err);
+    }
+
+    remove_item(source, &Progress::default()).map_err(|err| {
+        io::Error::other(format!(
+            "copied to {}, but removing the source failed: {err}",
+            target.display()
+        ))
+    })?;
+
+    Ok(MoveOutcome::CopiedAcross)
+}
+
+/// Copies a file, symlink or directory tree to `target`, which must not exist yet. Named pipes
+/// are created anew, the sockets and devices that have been left out are returned
+pub fn copy_recursive(
+    source: &Path,
+    target: &Path,
+    options: CopyOptions,
+    progress: &Progress,
+) -> Result<Vec<PathBuf>, CopyError> {
+    let metadata = fs::symlink_metadata(source).map_err(CopyError::at(source))?;
+
+    if metadata.is_dir() || options.follow_symlinks && source.is_dir() {
+        let canonical_source = source.canonicalize().map_err(CopyError::at(source))?;
+        let target_parent = target.parent().unwrap_or(target);
+        let canonical_parent = target_parent
+            .canonicalize()
+            .map_err(CopyError::at(target_parent))?;
+
+        if canonical_parent.starts_with(&canonical_source) {
+            return Err(CopyError {
+                path: target.to_path_buf(),
+                source: io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "can't copy a directory into itself",
+                ),
+            });
+        }
+    }
+
+    let mut copy = TreeCopy {
+        options,
+        progress,
+        ancestors: vec![],
+        skipped: vec![],
+    };
+
+    copy.entry(source, target)?;
+    Ok(copy.skipped)
+}
+
+impl TreeCopy<'_> {
+    fn entry(&mut self, source: &Path, target: &Path) -> Result<(), CopyError> {
+        self.progress
+            .check_cancelled()
+            .map_err(CopyError::at(source))?;
+
+        let metadata = if self.options.follow_symlinks {
+            fs::metadata(source)
+        } else {
+            fs::symlink_metadata(source)
+        }
+        .map_err(CopyError::at(source))?;
+
+        let file_type = metadata.file_type();
+
+        if file_type.is_symlink() {
+            let link = fs::read_link(source).map_err(CopyError::at(source))?;
+            symlink(link, target).map_err(CopyError::at(target))?;
+            self.progress.files_done.fetch_add(1, Ordering::Relaxed);
+
+            return Ok(());
+        }
+
+        if file_type.is_dir() {
+            let id = (metadata.dev(), metadata.ino());
+
+            if self.ancestors.contains(&id) {
+                return Err(CopyError {
+                    path: source.to_path_buf(),
+                    source: io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "symlink loop, it leads back to a directory that's being copied",
+                    ),
+                });
+            }
+
+            fs::create_dir(target).map_err(CopyError::at(target))?;
+            self.ancestors.push(id);
+
+            for entry in fs::read_dir(source).map_err(CopyError::at(source))? {
+                let entry = entry.map_err(CopyError::at(source))?;
+                self.entry(&entry.path(), &target.join(entry.file_name()))?;
+            }
+
+            self.ancestors.pop();
+        } else if file_type.is_fifo() {
+            make_fifo(target).map_err(CopyError::at(target))?;
+            self.progress.files_done.fetch_add(1, Ordering::Relaxed);
+        } else if file_type.is_socket() || file_type.is_block_device() || file_type.is_char_device()
+        {
+            // Opening these blocks or reads forever, and recreating devices needs root
+            self.skipped.push(source.to_path_buf());
+            self.progress.files_done.fetch_add(1, Ordering::Relaxed);
+
+            return Ok(());
+        } else {
+            copy_file(source, target, self.progress)?;
+            self.progress.files_done.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Directories get their attributes last, so copying the children doesn't undo them. The
+        // mtime goes first, the copied mode might not allow opening the target anymore
+        if self.options.preserve_mtime {
+            let modified = metadata.modified().map_err(CopyError::at(source))?;
+
+            // Non blocking, otherwise opening a named pipe waits for a writer
+            fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(target)
+                .and_then(|file| file.set_modified(modified))
+                .map_err(CopyError::at(target))?;
+        }
+
+        if self.options.preserve_mode {
+            fs::set_permissions(target, metadata.permissions()).map_err(CopyError::at(target))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn make_fifo(path: &Path) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o666) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Copies in chunks, so progress is reported and cancellation is noticed inside big files
+fn copy_file(source: &Path, target: &Path, progress: &Progress) -> Result<(), CopyError> {
+    let mut reader = fs::File::open(source).map_err(CopyError::at(source))?;
+    let mut writer = fs::File::create_new(target).map_err(CopyError::at(target))?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+
+    loop {
+        progress.check_cancelled().map_err(CopyError::at(source))?;
+
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(CopyError::at(source)(err)),
+        };
+
+        writer
+            .write_all(&buffer[..read])
+            .map_err(CopyError::at(target))?;
+
+        progress
+            .bytes_done
+            .fetch_add(read as u64, Ordering::Relaxed);
+    }
+}
+
+/// Checks that the target has the same tree, file types and file sizes as the source
+pub fn verify_copy(source: &Path, target: &Path) -> io::Result<()> {
+    let source_meta = fs::symlink_metadata(source)?;
+    let target_meta = fs::symlink_metadata(target)?;
+
+    let mismatch = || {
+        io::Error::other(format!(
+            "{} differs from {} after copying",
+            target.display(),
+            source.display()
+        ))
+    };
+
+    if source_meta.file_type() != target_meta.file_type() {
+        return Err(mismatch());
+    }
+
+    if source_meta.is_file() && source_meta.len() != target_meta.len() {
+        return Err(mismatch());
+    }
+
+    if source_meta.is_dir() {
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            verify_copy(&entry.path(), &target.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a file or a whole tree, one file at a time so it can report and be cancelled
+pub fn remove_item(path: &Path, progress: &Progress) -> io::Result<()> {
+    progress.check_cancelled()?;
+
+    let metadata = fs::symlink_metadata(path)?;
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            remove_item(&entry?.path(), progress)?;
+        }
+
+        fs::remove_dir(path)
+    } else {
+        fs::remove_file(path)?;
+        progress.files_done.fetch_add(1, Ordering::Relaxed);
+        progress
+            .bytes_done
+            .fetch_add(metadata.len(), Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{copy_recursive, make_fifo, verify_copy, CopyOptions, Progress, TreeTotals};
+    use crate::test_utils::TempDir;
+    use std::{
+        fs,
+        os::unix::{
+            fs::{symlink, FileTypeExt, PermissionsExt},
+            net::UnixListener,
+        },
+        sync::atomic::AtomicBool,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    pub fn copy_tree() {
+        let root = TempDir::new();
+        let source = root.join("source");
+        fs::create_dir_all(source.join("nested")).unwrap();
+
+        let file = source.join("nested/file.txt");
+        fs::write(&file, "content").unwrap();
+        symlink("nested/file.txt", source.join("link")).unwrap();
+
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::open(&file)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let target = root.join("target");
+        let progress = Progress::default();
+        progress.scan(&source);
+        copy_recursive(&source, &target, CopyOptions::default(), &progress).unwrap();
+        verify_copy(&source, &target).unwrap();
+
+        let copied = target.join("nested/file.txt");
+        assert_eq!(modified, fs::metadata(copied).unwrap().modified().unwrap());
+        assert!(fs::symlink_metadata(target.join("link"))
+            .unwrap()
+            .is_symlink());
+        assert_eq!(
+            progress.files_total.into_inner(),
+            progress.files_done.into_inner()
+        );
+    }
+
+    #[test]
+    pub fn copy_into_itself() {
+        let root = TempDir::new();
+        let source = root.join("source");
+        fs::create_dir_all(source.join("nested")).unwrap();
+
+        let err = copy_recursive(
+            &source,
+            &source.join("nested/copy"),
+            CopyOptions::default(),
+            &Progress::default(),
+        );
+        assert_eq!(source.join("nested/copy"), err.unwrap_err().path);
+    }
+
+    #[test]
+    pub fn special_files() {
+        let root = TempDir::new();
+        let source = root.join("source");
+        fs::create_dir_all(&source).unwrap();
+
+        make_fifo(&source.join("pipe")).unwrap();
+        let _listener = UnixListener::bind(source.join("socket")).unwrap();
+
+        let target = root.join("target");
+        let left_out = copy_recursive(
+            &source,
+            &target,
+            CopyOptions::default(),
+            &Progress::default(),
+        )
+        .unwrap();
+
+        assert_eq!(vec![source.join("socket")], left_out);
+        assert!(fs::symlink_metadata(target.join("pipe"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+        assert!(fs::symlink_metadata(target.join("socket")).is_err());
+    }
+
+    #[test]
+    pub fn follow_symlinks() {
+        let root = TempDir::new();
+        let source = root.join("source");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::write(source.join("nested/file.txt"), "content").unwrap();
+        symlink("nested/file.txt", source.join("link")).unwrap();
+
+        let options = CopyOptions {
+            follow_symlinks: true,
+            ..CopyOptions::default()
+        };
+
+        let target = root.join("target");
+        copy_recursive(&source, &target, options, &Progress::default()).unwrap();
+
+        let link = fs::symlink_metadata(target.join("link")).unwrap();
+        assert!(link.is_file());
+        assert_eq!("content", fs::read_to_string(target.join("link")).unwrap());
+
+        // Leads back to the directory that contains it
+        symlink("..", source.join("nested/parent")).unwrap();
+
+        let err = copy_recursive(&source, &root.join("loop"), options, &Progress::default());
+        assert_eq!(source.join("nested/parent"), err.unwrap_err().path);
+    }
+
+    #[test]
+    pub fn without_mode() {
+        let root = TempDir::new();
+        let source = root.join("script.sh");
+        fs::write(&source, "").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o750)).unwrap();
+
+        let options = CopyOptions {
+            preserve_mode: false,
+            ..CopyOptions::default()
+        };
+
+        let kept = root.join("kept.sh");
+        copy_recursive(&source, &kept, CopyOptions::default(), &Progress::default()).unwrap();
+        assert_eq!(
+            0o750,
+            fs::metadata(kept).unwrap().permissions().mode() & 0o777
+        );
+
+        let fresh = root.join("fresh.sh");
+        copy_recursive(&source, &fresh, options, &Progress::default()).unwrap();
+        assert_eq!(0, fs::metadata(fresh).unwrap().permissions().mode() & 0o111);
+    }
+
+    #[test]
+    pub fn tree_totals() {
+        let root = TempDir::new();
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::write(root.join("dir/a.txt"), "hello").unwrap();
+        fs::write(root.join("dir/sub/b.txt"), "abc").unwrap();
+        fs::write(root.join("c.txt"), "c").unwrap();
+
+        let paths = [root.join("dir"), root.join("c.txt"), root.join("missing")];
+        let totals = TreeTotals::count(&paths, &AtomicBool::new(false)).unwrap();
+
+        assert_eq!((3, 2, 9), (totals.files, totals.dirs, totals.bytes));
+        assert!(TreeTotals::count(&paths, &AtomicBool::new(true)).is_none());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::error::TravellerResult;
+use crate::icons::set_hl;
+use crate::utils::NeoUtils;
+use neo_api_rs::mlua::prelude::*;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Ordered by importance, a directory shows the most important status of its content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GitStatus {
+    Ignored,
+    ContainsChanges,
+    Untracked,
+    Staged,
+    Modified,
+    Conflicted,
+}
+
+impl GitStatus {
+    pub fn marker(&self) -> &'static str {
+        match self {
+            Self::Ignored => "!",
+            Self::ContainsChanges => "•",
+            Self::Untracked => "?",
+            Self::Staged => "S",
+            Self::Modified => "M",
+            Self::Conflicted => "U",
+        }
+    }
+
+    pub fn hl_group(&self) -> &'static str {
+        match self {
+            Self::Ignored => "TravellerGitIgnored",
+            Self::ContainsChanges => "TravellerGitContainsChanges",
+            Self::Untracked => "TravellerGitUntracked",
+            Self::Staged => "TravellerGitStaged",
+            Self::Modified => "TravellerGitModified",
+            Self::Conflicted => "TravellerGitConflicted",
+        }
+    }
+
+    fn default_link(&self) -> &'static str {
+        match self {
+            Self::Ignored => "Comment",
+            Self::ContainsChanges => "DiagnosticInfo",
+            Self::Untracked => "DiagnosticHint",
+            Self::Staged => "DiagnosticOk",
+            Self::Modified => "DiagnosticWarn",
+            Self::Conflicted => "DiagnosticError",
+        }
+    }
+}
+
+/// Links the groups with `default`, so colour schemes and users can override them
+pub fn define_git_highlights(lua: &Lua) -> LuaResult<()> {
+    let statuses = [
+        GitStatus::Ignored,
+        GitStatus::ContainsChanges,
+        GitStatus::Untracked,
+        GitStatus::Staged,
+        GitStatus::Modified,
+        GitStatus::Conflicted,
+    ];
+
+    for status in statuses {
+        let opts = lua.create_table()?;
+        opts.set("link", status.default_link())?;
+        opts.set("default", true)?;
+
+        set_hl(lua, status.hl_group(), opts)?;
+    }
+
+    Ok(())
+}
+
+/// Status of the items of the listing, from one `git status` limited to the directory
+pub fn git_statuses(
+    dir_path: &Path,
+    git_root: &Path,
+    items: &[String],
+) -> TravellerResult<HashMap<String, GitStatus>> {
+    // Git reports the resolved paths, the listing may be reached through a symlink
+    let dir_path = fs::canonicalize(dir_path).unwrap_or_else(|_| dir_path.to_path_buf());
+    let Ok(relative_dir) = dir_path.strip_prefix(git_root) else {
+        return Ok(HashMap::new());
+    };
+
+    // The paths stay relative to the repository root
+    let args = ["status", "--porcelain=v2", "-z", "--ignored", "--", "."];
+    let output = NeoUtils::git_output(&dir_path, &args)?;
+    let index = StatusIndex::parse(&output);
+
+    let statuses = items
+        .iter()
+        .filter_map(|item| {
+            let path = NeoUtils::item_path(relative_dir, item);
+            let status = index.item_status(&path, item.ends_with('/'))?;
+
+            Some((item.clone(), status))
+        })
+        .collect();
+
+    Ok(statuses)
+}
+
+/// Statuses by path relative to the repository root, so every row is a lookup
+#[derive(Debug, Default)]
+struct StatusIndex {
+    /// Reported paths. Directories that are untracked or ignored as a whole are reported
+    /// themselves instead of their content
+    paths: HashMap<PathBuf, GitStatus>,
+    /// Most important status of the changes inside every directory that contains some
+    contents: HashMap<PathBuf, GitStatus>,
+}
+
+impl StatusIndex {
+    fn parse(output: &str) -> Self {
+        let mut index = Self::default();
+
+        for (path, status) in parse_porcelain(output) {
+            if status != GitStatus::Ignored {
+                for dir in path.ancestors().skip(1) {
+                    let content = index.contents.entry(dir.to_path_buf()).or_insert(status);
+                    *content = status.max(*content);
+                }
+            }
+
+            let own = index.paths.entry(path).or_insert(status);
+            *own = status.max(*own);
+        }
+
+        index
+    }
+
+    fn item_status(&self, path: &Path, is_dir: bool) -> Option<GitStatus> {
+        // Whole directories are reported with a trailing slash, `Path` equality ignores it
+        if let Some(status) = self.paths.get(path) {
+            return Some(*status);
+        }
+
+        // Everything inside an untracked or ignored directory is untracked or ignored as well
+        let inherited = path
+            .ancestors()
+            .skip(1)
+            .filter_map(|dir| self.paths.get(dir))
+            .find(|status| matches!(status, GitStatus::Untracked | GitStatus::Ignored));
+
+        if inherited.is_some() || !is_dir {
+            return inherited.copied();
+        }
+
+        Some(match self.contents.get(path)? {
+            GitStatus::Conflicted => GitStatus::Conflicted,
+            _ => GitStatus::ContainsChanges,
+        })
+    }
+}
+
+/// Paths relative to the repository root with their status. Directories that are untracked or
+/// ignored as a whole end with a slash
+fn parse_porcelain(output: &str) -> Vec<(PathBuf, GitStatus)> {
+    let mut entries = vec![];
+    let mut fields = output.split('\0');
+
+    while let Some(line) = fields.next() {
+        let status = match line.as_bytes().first() {
+            Some(b'1') => changed_status(line, 8),
+            Some(b'2') => {
+                // Renames are followed by the original path
+                fields.next();
+                changed_status(line, 9)
+            }
+            Some(b'u') => Some((line.splitn(11, ' ').nth(10), GitStatus::Conflicted)),
+            Some(b'?') => Some((line.get(2..), GitStatus::Untracked)),
+            Some(b'!') => Some((line.get(2..), GitStatus::Ignored)),
+            _ => None,
+        };
+
+        if let Some((Some(path), status)) = status {
+            entries.push((PathBuf::from(path), status));
+        }
+    }
+
+    entries
+}
+
+/// `1 XY ...` and `2 XY ...` lines, X is the index and Y the work tree
+fn changed_status(line: &str, path_field: usize) -> Option<(Option<&str>, GitStatus)> {
+    let xy = line.get(2..4)?;
+    let path = line.splitn(path_field + 1, ' ').nth(path_field);
+
+    let status = if !xy.ends_with('.') {
+        GitStatus::Modified
+    } else {
+        GitStatus::Staged
+    };
+
+    Some((path, status))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{git_statuses, parse_porcelain, GitStatus, StatusIndex};
+    use crate::test_utils::TempDir;
+    use std::{collections::HashMap, fs, path::Path, process::Command};
+
+    #[test]
+    pub fn porcelain_status() {
+        let output = [
+            "1 .M N... 100644 100644 100644 abc abc src/main.rs",
+            "1 A. N... 000000 100644 100644 000 abc src/new file.rs",
+            "2 R. N... 100644 100644 100644 abc abc R100 docs/guide.md",
+            "docs/old.md",
+            "u UU N... 100644 100644 100644 100644 a b c Cargo.toml",
+            "? notes/",
+            "! target/",
+            "",
+        ]
+        .join("\0");
+
+        assert_eq!(6, parse_porcelain(&output).len());
+
+        let index = StatusIndex::parse(&output);
+        let status = |path: &str, is_dir| index.item_status(Path::new(path), is_dir);
+
+        assert_eq!(Some(GitStatus::Modified), status("src/main.rs", false));
+        assert_eq!(Some(GitStatus::Staged), status("src/new file.rs", false));
+        assert_eq!(Some(GitStatus::Staged), status("docs/guide.md", false));
+        assert_eq!(None, status("docs/old.md", false));
+        assert_eq!(Some(GitStatus::Conflicted), status("Cargo.toml", false));
+        assert_eq!(Some(GitStatus::Untracked), status("notes", true));
+        assert_eq!(Some(GitStatus::Ignored), status("target", true));
+        assert_eq!(Some(GitStatus::ContainsChanges), status("src", true));
+        assert_eq!(None, status("README.md", false));
+    }
+
+    #[test]
+    pub fn inherited_status() {
+        let output = [
+            "? notes/",
+            "! target/",
+            "1 .M N... 100644 100644 100644 abc abc notes.rs",
+            "",
+        ]
+        .join("\0");
+
+        let index = StatusIndex::parse(&output);
+        let status = |path: &str, is_dir| index.item_status(Path::new(path), is_dir);
+
+        assert_eq!(Some(GitStatus::Untracked), status("notes/todo.md", false));
+        assert_eq!(Some(GitStatus::Untracked), status("notes/drafts", true));
+        assert_eq!(
+            Some(GitStatus::Ignored),
+            status("target/debug/build.log", false)
+        );
+        assert_eq!(None, status("notes.rs/x", false));
+    }
+
+    #[test]
+    pub fn statuses_of_a_directory() {
+        let root = TempDir::new();
+        let status = Command::new("git")
+            .arg("init")
+            .arg("-q")
+            .arg(&*root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        fs::create_dir_all(root.join("sub/[x]")).unwrap();
+        fs::create_dir_all(root.join("other")).unwrap();
+        fs::write(root.join("sub/[x]/a.txt"), "").unwrap();
+        fs::write(root.join("other/b.txt"), "").unwrap();
+        fs::write(root.join("sub/staged.txt"), "").unwrap();
+
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(&*root)
+            .args(["add", "sub/staged.txt"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let git_root = root.canonicalize().unwrap();
+        let items = ["[x]/", "staged.txt", "missing.txt"].map(String::from);
+
+        assert_eq!(
+            HashMap::from([
+                ("[x]/".to_string(), GitStatus::Untracked),
+                ("staged.txt".to_string(), GitStatus::Staged),
+            ]),
+            git_statuses(&root.join("sub"), &git_root, &items).unwrap()
+        );
+    }
+}
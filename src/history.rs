@@ -0,0 +1,280 @@
+use crate::error::{TravellerError, TravellerResult};
+use crate::state::Location;
+use crate::utils::NeoUtils;
+use crate::CONTAINER;
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::*;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// First line of the file, files of other versions are ignored instead of misread
+const HEADER: &str = "traveller-history 1";
+const FILE_NAME: &str = "history";
+/// Directories remembered, the least recently visited ones are dropped first
+const MAX_LOCATIONS: usize = 1000;
+
+/// Where the cursor was left per directory, kept under `stdpath("state")/traveller`
+pub fn history_dir(lua: &Lua) -> LuaResult<PathBuf> {
+    Ok(NeoApi::stdpath(lua, StdpathType::State)?.join("traveller"))
+}
+
+/// Locations recorded while navigating, kept in memory until they're flushed to disk
+#[derive(Debug, Default)]
+pub struct History {
+    dir: PathBuf,
+    /// Most recently visited first, not written yet
+    pending: Vec<Location>,
+}
+
+impl History {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            pending: vec![],
+        }
+    }
+
+    /// Most recently visited first, the locations that aren't written yet included
+    pub fn load(&self) -> Vec<Location> {
+        merge(self.pending.clone(), load(&self.dir))
+    }
+
+    /// Moves the location to the front of the history
+    pub fn record(&mut self, location: Location) {
+        self.pending.retain(|loc| loc.dir_path != location.dir_path);
+        self.pending.insert(0, location);
+        self.pending.truncate(MAX_LOCATIONS);
+    }
+
+    /// Writes the pending locations. The file is read again first, so other instances and Neovim
+    /// sessions keep their locations. On failure they stay pending
+    pub fn flush(&mut self) -> TravellerResult<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let locations = self.load();
+        fs::create_dir_all(&self.dir).map_err(TravellerError::io(&self.dir))?;
+
+        // Renamed over the old file, so a crash while writing can't leave half of it behind
+        let tmp_path = self.dir.join(format!("{FILE_NAME}.{}", std::process::id()));
+        let path = self.dir.join(FILE_NAME);
+
+        fs::write(&tmp_path, serialize(&locations)).map_err(TravellerError::io(&tmp_path))?;
+        fs::rename(&tmp_path, &path).map_err(TravellerError::io(&path))?;
+
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// Writes what's left when Neovim quits, navigation buffers still open aren't wiped out first
+pub fn flush_on_leave(lua: &Lua) -> LuaResult<()> {
+    let api: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("api")?;
+    let create_autocmd: LuaFunction = api.get("nvim_create_autocmd")?;
+
+    let flush = lua.create_async_function(|_, ()| async {
+        // Neovim is quitting, there is nobody left to tell
+        let _ = CONTAINER.history.write().await.flush();
+
+        Ok(())
+    })?;
+
+    let opts = lua.create_table()?;
+    opts.set("callback", flush)?;
+
+    create_autocmd.call::<_, u32>(("VimLeavePre", opts))?;
+
+    Ok(())
+}
+
+/// `locations` in front of the ones of `older` that are for other directories
+fn merge(mut locations: Vec<Location>, older: Vec<Location>) -> Vec<Location> {
+    let mut seen: HashSet<PathBuf> = locations.iter().map(|loc| loc.dir_path.clone()).collect();
+
+    for location in older {
+        if seen.insert(location.dir_path.clone()) {
+            locations.push(location);
+        }
+    }
+
+    locations.truncate(MAX_LOCATIONS);
+    locations
+}
+
+/// Most recently visited first. A missing or unreadable file is an empty history, and lines that
+/// can't be parsed are skipped
+fn load(history_dir: &Path) -> Vec<Location> {
+    fs::read(history_dir.join(FILE_NAME))
+        .map(|content| parse(&String::from_utf8_lossy(&content)))
+        .unwrap_or_default()
+}
+
+fn parse(content: &str) -> Vec<Location> {
+    let mut lines = content.lines();
+
+    if lines.next() != Some(HEADER) {
+        return vec![];
+    }
+
+    let mut locations: Vec<Location> = vec![];
+    let mut seen = HashSet::new();
+
+    for line in lines {
+        let Some((dir, item)) = line.split_once('\t') else {
+            continue;
+        };
+
+        let dir_path = PathBuf::from(NeoUtils::unescape_name(dir));
+
+        if item.is_empty() || !dir_path.is_absolute() {
+            continue;
+        }
+
+        if !seen.insert(dir_path.clone()) {
+            continue;
+        }
+
+        locations.push(Location::new(dir_path, item.to_string()));
+    }
+
+    locations.truncate(MAX_LOCATIONS);
+    locations
+}
+
+/// Paths are escaped, so tabs and newlines in names can't break the lines
+fn serialize(locations: &[Location]) -> String {
+    let mut content = format!("{HEADER}\n");
+
+    for location in locations.iter().take(MAX_LOCATIONS) {
+        let dir = NeoUtils::escape_name(location.dir_path.as_os_str());
+        content.push_str(&format!("{dir}\t{}\n", location.item));
+    }
+
+    content
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, History, FILE_NAME, HEADER, MAX_LOCATIONS};
+    use crate::error::{TravellerError, TravellerResult};
+    use crate::state::Location;
+    use crate::test_utils::TempDir;
+    use std::{fs, path::PathBuf};
+
+    fn location(dir: &str, item: &str) -> Location {
+        Location::new(PathBuf::from(dir), item.to_string())
+    }
+
+    #[test]
+    pub fn persisted_history() {
+        let root = TempDir::new();
+        assert!(load(&root).is_empty());
+
+        let mut history = History::new(root.to_path_buf());
+        history.record(location("/project", "src/"));
+        history.record(location("/project/src", "lib.rs"));
+        history.flush().unwrap();
+
+        history.record(location("/project", "Cargo.toml"));
+        history.record(location("/tmp/a\tb\nc", "x"));
+        history.flush().unwrap();
+
+        let items: Vec<_> = load(&root)
+            .into_iter()
+            .map(|loc| (loc.dir_path, loc.item))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (PathBuf::from("/tmp/a\tb\nc"), "x".to_string()),
+                (PathBuf::from("/project"), "Cargo.toml".to_string()),
+                (PathBuf::from("/project/src"), "lib.rs".to_string()),
+            ],
+            items
+        );
+    }
+
+    #[test]
+    pub fn corrupted_history() {
+        let root = TempDir::new();
+        let path = root.join(FILE_NAME);
+
+        // Broken lines are skipped, the rest of the file is kept
+        let content = format!("{HEADER}\ngarbage\nrelative\tx\n/docs\t\n/docs\tguide.md\n");
+        fs::write(&path, content).unwrap();
+
+        let locations = load(&root);
+        assert_eq!(1, locations.len());
+        assert_eq!("guide.md", locations[0].item);
+
+        fs::write(&path, "traveller-history 0\n/docs\tguide.md\n").unwrap();
+        assert!(load(&root).is_empty());
+    }
+
+    #[test]
+    pub fn history_size_cap() {
+        let root = TempDir::new();
+
+        let lines: String = (0..MAX_LOCATIONS + 10)
+            .map(|i| format!("/dir{i}\titem\n"))
+            .collect();
+        fs::write(root.join(FILE_NAME), format!("{HEADER}\n{lines}")).unwrap();
+
+        let mut history = History::new(root.to_path_buf());
+        history.record(location("/project", "src/"));
+        history.flush().unwrap();
+
+        let locations = load(&root);
+        assert_eq!(MAX_LOCATIONS, locations.len());
+        assert_eq!(PathBuf::from("/project"), locations[0].dir_path);
+        assert_eq!(
+            PathBuf::from(format!("/dir{}", MAX_LOCATIONS - 2)),
+            locations[MAX_LOCATIONS - 1].dir_path
+        );
+    }
+
+    #[test]
+    pub fn buffered_history() {
+        let root = TempDir::new();
+        fs::write(
+            root.join(FILE_NAME),
+            format!("{HEADER}\n/project\tsrc/\n/docs\tguide.md\n"),
+        )
+        .unwrap();
+
+        let mut history = History::new(root.to_path_buf());
+        history.record(location("/project", "Cargo.toml"));
+
+        // Nothing is written until it's flushed, but it's already loaded
+        assert_eq!("src/", load(&root)[0].item);
+        assert_eq!("Cargo.toml", history.load()[0].item);
+        assert_eq!(2, history.load().len());
+
+        history.flush().unwrap();
+
+        let items: Vec<_> = load(&root).into_iter().map(|loc| loc.item).collect();
+        assert_eq!(vec!["Cargo.toml", "guide.md"], items);
+    }
+
+    #[test]
+    pub fn unwritable_history() {
+        let root = TempDir::new();
+        let dir = root.join("state");
+        fs::write(&dir, "").unwrap();
+
+        let mut history = History::new(dir.clone());
+        history.record(location("/project", "src/"));
+
+        let err = history.flush().unwrap_err();
+        assert!(err.to_string().starts_with(&dir.display().to_string()));
+
+        // Kept for the next flush
+        fs::remove_file(&dir).unwrap();
+        history.flush().unwrap();
+        assert_eq!("src/", load(&dir)[0].item);
+    }
+}
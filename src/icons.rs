@@ -0,0 +1,116 @@
+use neo_api_rs::mlua::prelude::*;
+use std::path::Path;
+
+/// Icon and highlight group of a file type, the group is defined with `color` as foreground
+pub struct FileIcon {
+    pub icon: &'static str,
+    pub hl_group: &'static str,
+    pub color: &'static str,
+}
+
+const fn icon(icon: &'static str, hl_group: &'static str, color: &'static str) -> FileIcon {
+    FileIcon {
+        icon,
+        hl_group,
+        color,
+    }
+}
+
+pub const DIRECTORY: FileIcon = icon("\u{f07b}", "TravellerIconDirectory", "#7ebae4");
+pub const FILE: FileIcon = icon("\u{f15b}", "TravellerIconFile", "#8c8c8c");
+
+/// Nerd Font icons by extension
+const EXTENSIONS: [(&str, FileIcon); 32] = [
+    ("rs", icon("\u{e7a8}", "TravellerIconRust", "#dea584")),
+    ("toml", icon("\u{e6b2}", "TravellerIconToml", "#9c4221")),
+    ("lock", icon("\u{f023}", "TravellerIconLock", "#bbbbbb")),
+    ("lua", icon("\u{e620}", "TravellerIconLua", "#51a0cf")),
+    ("vim", icon("\u{e62b}", "TravellerIconVim", "#019833")),
+    ("c", icon("\u{e61e}", "TravellerIconC", "#599eff")),
+    ("h", icon("\u{f0fd}", "TravellerIconC", "#599eff")),
+    ("cpp", icon("\u{e61d}", "TravellerIconCpp", "#519aba")),
+    ("go", icon("\u{e627}", "TravellerIconGo", "#00add8")),
+    ("py", icon("\u{e606}", "TravellerIconPython", "#ffbc03")),
+    ("js", icon("\u{e74e}", "TravellerIconJavascript", "#cbcb41")),
+    ("ts", icon("\u{e628}", "TravellerIconTypescript", "#519aba")),
+    ("json", icon("\u{e60b}", "TravellerIconJson", "#cbcb41")),
+    ("html", icon("\u{e736}", "TravellerIconHtml", "#e44d26")),
+    ("css", icon("\u{e749}", "TravellerIconCss", "#42a5f5")),
+    ("md", icon("\u{e73e}", "TravellerIconMarkdown", "#dddddd")),
+    ("txt", icon("\u{f15c}", "TravellerIconText", "#89e051")),
+    ("sh", icon("\u{e795}", "TravellerIconShell", "#4d5a5e")),
+    ("yml", icon("\u{e6a8}", "TravellerIconYaml", "#6d8086")),
+    ("yaml", icon("\u{e6a8}", "TravellerIconYaml", "#6d8086")),
+    ("png", icon("\u{f1c5}", "TravellerIconImage", "#a074c4")),
+    ("jpg", icon("\u{f1c5}", "TravellerIconImage", "#a074c4")),
+    ("jpeg", icon("\u{f1c5}", "TravellerIconImage", "#a074c4")),
+    ("gif", icon("\u{f1c5}", "TravellerIconImage", "#a074c4")),
+    ("svg", icon("\u{f1c5}", "TravellerIconSvg", "#ffb13b")),
+    ("pdf", icon("\u{f1c1}", "TravellerIconPdf", "#b30b00")),
+    ("zip", icon("\u{f410}", "TravellerIconArchive", "#eca517")),
+    ("gz", icon("\u{f410}", "TravellerIconArchive", "#eca517")),
+    ("tar", icon("\u{f410}", "TravellerIconArchive", "#eca517")),
+    ("mp3", icon("\u{f1c7}", "TravellerIconAudio", "#66d8ef")),
+    ("mp4", icon("\u{f1c8}", "TravellerIconVideo", "#fd971f")),
+    ("gitignore", icon("\u{e702}", "TravellerIconGit", "#f54d27")),
+];
+
+/// The icon of a listing item, directories end with a slash
+pub fn file_icon(item_name: &str) -> &'static FileIcon {
+    if item_name.ends_with('/') {
+        return &DIRECTORY;
+    }
+
+    let extension = Path::new(item_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        // Dotfiles like .gitignore have no extension, but are recognized by their name
+        .or_else(|| item_name.strip_prefix('.'));
+
+    extension
+        .and_then(|ext| {
+            EXTENSIONS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(ext))
+        })
+        .map_or(&FILE, |(_, icon)| icon)
+}
+
+/// Defines the icon groups with `default`, so colour schemes and users can override them
+pub fn define_icon_highlights(lua: &Lua) -> LuaResult<()> {
+    let icons = EXTENSIONS.iter().map(|(_, icon)| icon);
+
+    for icon in [&DIRECTORY, &FILE].into_iter().chain(icons) {
+        let opts = lua.create_table()?;
+        opts.set("fg", icon.color)?;
+        opts.set("default", true)?;
+
+        set_hl(lua, icon.hl_group, opts)?;
+    }
+
+    Ok(())
+}
+
+pub fn set_hl(lua: &Lua, name: &str, opts: LuaTable) -> LuaResult<()> {
+    let api: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("api")?;
+    let set_hl: LuaFunction = api.get("nvim_set_hl")?;
+
+    set_hl.call((0, name, opts))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DIRECTORY, EXTENSIONS, FILE};
+    use std::collections::HashMap;
+
+    #[test]
+    pub fn one_color_per_group() {
+        let mut colors = HashMap::new();
+        let icons = EXTENSIONS.iter().map(|(_, icon)| icon);
+
+        for icon in [&DIRECTORY, &FILE].into_iter().chain(icons) {
+            let color = colors.entry(icon.hl_group).or_insert(icon.color);
+            assert_eq!(*color, icon.color, "{}", icon.hl_group);
+        }
+    }
+}
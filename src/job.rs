@@ -0,0 +1,550 @@
+use crate::edit::EditPlan;
+use crate::error::{TravellerError, TravellerResult};
+use crate::fs_ops::{Progress, TreeTotals};
+use crate::journal::{FsOperation, JournalStep, Replay};
+use crate::paste::PastePlan;
+use crate::state::{delete_item, AppState};
+use crate::utils::NeoUtils;
+use crate::CONTAINER;
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+const POLL_INTERVAL_MS: u32 = 100;
+const BUSY_MESSAGE: &str = "Wait for the running file operation to finish";
+
+/// Filesystem work that runs on the runtime instead of inside a keymap callback
+#[derive(Debug)]
+pub enum JobKind {
+    Paste(PastePlan),
+    Delete {
+        paths: Vec<PathBuf>,
+        permanent: bool,
+    },
+    /// Edits made to the navigation buffers as text
+    Edit(EditPlan),
+    /// Undo or redo of a step of the journal
+    Journal(Replay),
+}
+
+#[derive(Debug, Clone)]
+pub struct PreviewLine {
+    pub text: String,
+    pub highlight: Option<&'static str>,
+}
+
+#[derive(Debug, Default)]
+pub struct JobSummary {
+    pub messages: Vec<String>,
+    pub failed: bool,
+    pub operations: Vec<FsOperation>,
+    /// Goes back into the journal once it has run
+    pub replay: Option<Replay>,
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub title: String,
+    pub progress: Arc<Progress>,
+    /// Directories that need to be refreshed once the job is done
+    pub dirs: Vec<PathBuf>,
+    popup: NeoPopup,
+    handle: JoinHandle<(JobSummary, JournalStep)>,
+}
+
+impl JobKind {
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Paste(plan) if plan.copy => " Copying ",
+            Self::Paste(_) => " Moving ",
+            Self::Delete {
+                permanent: true, ..
+            } => " Deleting ",
+            Self::Delete { .. } => " Moving to trash ",
+            Self::Edit(_) => " Applying edits ",
+            Self::Journal(replay) if replay.undo => " Undoing ",
+            Self::Journal(_) => " Redoing ",
+        }
+    }
+
+    /// Every planned operation, grouped by the directory it comes from, followed by the totals
+    pub fn preview(&self) -> TravellerResult<Vec<PreviewLine>> {
+        let (header, mut items): (String, Vec<(&Path, Option<&Path>, bool)>) = match self {
+            Self::Paste(plan) => {
+                let target_dir = plan
+                    .items
+                    .first()
+                    .and_then(|item| item.target.parent())
+                    .unwrap_or(Path::new(""));
+
+                let action = if plan.copy { "Copy" } else { "Move" };
+                let header = format!(
+                    "{action} {} item(s) into {}",
+                    plan.items.len(),
+                    target_dir.display()
+                );
+
+                let items = plan
+                    .items
+                    .iter()
+                    .map(|item| {
+                        (
+                            item.source.as_path(),
+                            Some(item.target.as_path()),
+                            item.conflict,
+                        )
+                    })
+                    .collect();
+
+                (header, items)
+            }
+            Self::Delete { paths, permanent } => {
+                let header = if *permanent {
+                    format!("Delete {} item(s) permanently", paths.len())
+                } else {
+                    format!("Move {} item(s) to the trash", paths.len())
+                };
+
+                let items = paths
+                    .iter()
+                    .map(|path| (path.as_path(), None, false))
+                    .collect();
+
+                (header, items)
+            }
+            Self::Edit(plan) => return Ok(plan.preview()),
+            Self::Journal(_) => {
+                return Err(TravellerError::InvalidInput(
+                    "Undo and redo have no preview".to_string(),
+                ))
+            }
+        };
+
+        items.sort_by(|a, b| a.0.cmp(b.0));
+
+        // The same name selected in different directories ends up on the same target
+        let mut by_target: HashMap<&Path, Vec<&Path>> = HashMap::new();
+
+        for (source, target, _) in &items {
+            if let Some(target) = target {
+                by_target.entry(target).or_default().push(source);
+            }
+        }
+
+        let mut lines = vec![PreviewLine {
+            text: header,
+            highlight: Some("Title"),
+        }];
+
+        let mut current_dir = None;
+
+        for (source, target, conflict) in items {
+            let dir = source.parent();
+
+            if dir != current_dir {
+                current_dir = dir;
+                lines.push(PreviewLine {
+                    text: format!("{}/", dir.unwrap_or(Path::new("")).display()),
+                    highlight: Some("Directory"),
+                });
+            }
+
+            let missing = source.symlink_metadata().is_err();
+            let name = preview_name(source);
+
+            let mut text = match target {
+                Some(target) if name.ends_with('/') => {
+                    format!("  {name} -> {}/", target.display())
+                }
+                Some(target) => format!("  {name} -> {}", target.display()),
+                None => format!("  {name}"),
+            };
+
+            let same_name = target
+                .and_then(|target| by_target.get(target))
+                .and_then(|sources| sources.iter().find(|other| **other != source));
+
+            let highlight = if missing {
+                text.push_str("  [missing]");
+                Some("ErrorMsg")
+            } else if let Some(other) = same_name {
+                text.push_str(&format!("  [same name as {}]", other.display()));
+                Some("WarningMsg")
+            } else if conflict {
+                text.push_str("  [conflict]");
+                Some("WarningMsg")
+            } else {
+                None
+            };
+
+            lines.push(PreviewLine { text, highlight });
+        }
+
+        // Filled in by `count_preview_totals`, large trees take a while to walk
+        lines.push(PreviewLine {
+            text: "Total: counting...".to_string(),
+            highlight: Some("Title"),
+        });
+
+        Ok(lines)
+    }
+
+    /// Trees the totals of the preview are counted over
+    pub fn preview_sources(&self) -> Vec<PathBuf> {
+        match self {
+            Self::Paste(plan) => plan.items.iter().map(|item| item.source.clone()).collect(),
+            Self::Delete { paths, .. } => paths.clone(),
+            Self::Edit(_) | Self::Journal(_) => vec![],
+        }
+    }
+
+    fn affected_dirs(&self) -> Vec<PathBuf> {
+        let paths: Vec<&Path> = match self {
+            Self::Paste(plan) => plan
+                .items
+                .iter()
+                .flat_map(|item| [item.source.as_path(), item.target.as_path()])
+                .collect(),
+            Self::Delete { paths, .. } => paths.iter().map(PathBuf::as_path).collect(),
+            Self::Edit(plan) => plan.paths(),
+            Self::Journal(replay) => return replay.affected_dirs(),
+        };
+
+        let mut dirs: Vec<PathBuf> = paths
+            .into_iter()
+            .filter_map(Path::parent)
+            .map(Path::to_path_buf)
+            .collect();
+
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+
+    fn execute(self, progress: &Progress) -> JobSummary {
+        match self {
+            Self::Edit(plan) => plan.execute(progress),
+            Self::Journal(mut replay) => {
+                let result = replay.run(progress);
+                let action = if replay.undo { "Undo" } else { "Redo" };
+
+                let (done, total) = (replay.done_count(), replay.operation_count());
+
+                let message = match &result {
+                    Ok(()) => format!("{action}: {total} operation(s)"),
+                    Err(err) if done == 0 => format!("{action} failed: {err}"),
+                    Err(err) => format!(
+                        "{action} failed after {done} of {total} operation(s), the rest can be \
+                         retried: {err}"
+                    ),
+                };
+
+                JobSummary {
+                    messages: vec![message],
+                    failed: result.is_err(),
+                    operations: vec![],
+                    replay: Some(replay),
+                }
+            }
+            Self::Paste(plan) => {
+                let summary = plan.execute(progress);
+
+                JobSummary {
+                    messages: summary.messages(),
+                    failed: !summary.failed.is_empty(),
+                    operations: summary.operations,
+                    replay: None,
+                }
+            }
+            Self::Delete { paths, permanent } => {
+                let totals: Vec<_> = paths.iter().map(|path| progress.scan(path)).collect();
+                let mut done = (0, 0);
+
+                let mut summary = JobSummary::default();
+                let mut deleted = 0;
+
+                for (path, (files, bytes)) in paths.iter().zip(totals) {
+                    if progress.is_cancelled() {
+                        break;
+                    }
+
+                    match delete_item(path, permanent, progress) {
+                        Ok(operation) => {
+                            summary.operations.extend(operation);
+                            deleted += 1;
+                        }
+                        Err(err) => {
+                            summary.failed = true;
+                            summary
+                                .messages
+                                .push(format!("Failed {}: {err}", path.display()));
+                        }
+                    }
+
+                    done = (done.0 + files, done.1 + bytes);
+                    progress.settle(done.0, done.1);
+                }
+
+                let mut header = format!("Deleted: {deleted}, of: {}", paths.len());
+
+                if progress.is_cancelled() {
+                    header.push_str(" (cancelled)");
+                }
+
+                summary.messages.insert(0, header);
+                summary
+            }
+        }
+    }
+}
+
+/// Counts the sources in the background and replaces the last line of the preview, at `row`,
+/// with the totals. Stops once `cancelled` is set, the preview is closed by then
+pub fn count_preview_totals(
+    lua: &Lua,
+    buf: NeoBuffer,
+    row: usize,
+    sources: Vec<PathBuf>,
+    cancelled: Arc<AtomicBool>,
+) -> LuaResult<()> {
+    let totals = Arc::new(Mutex::new(None));
+
+    let task_totals = totals.clone();
+    let task_cancelled = cancelled.clone();
+
+    RTM.spawn_blocking(move || {
+        let counted = TreeTotals::count(&sources, &task_cancelled);
+
+        if let Ok(mut totals) = task_totals.lock() {
+            *totals = counted;
+        }
+    });
+
+    let poll = lua.create_async_function(move |lua, ()| {
+        poll_preview_totals(lua, buf, row, totals.clone(), cancelled.clone())
+    })?;
+
+    NeoApi::delay(lua, POLL_INTERVAL_MS, poll)
+}
+
+async fn poll_preview_totals(
+    lua: &Lua,
+    buf: NeoBuffer,
+    row: usize,
+    totals: Arc<Mutex<Option<TreeTotals>>>,
+    cancelled: Arc<AtomicBool>,
+) -> LuaResult<()> {
+    if cancelled.load(Ordering::Relaxed) || !buf_is_valid(lua, buf)? {
+        return Ok(());
+    }
+
+    let counted = totals.lock().ok().and_then(|totals| *totals);
+
+    let Some(counted) = counted else {
+        let poll = lua.create_async_function(move |lua, ()| {
+            poll_preview_totals(lua, buf, row, totals.clone(), cancelled.clone())
+        })?;
+
+        return NeoApi::delay(lua, POLL_INTERVAL_MS, poll);
+    };
+
+    let line = format!(
+        "Total: {} file(s), {} dir(s), {}",
+        counted.files,
+        counted.dirs,
+        NeoUtils::human_size(counted.bytes)
+    );
+
+    buf.set_lines(lua, row as i32, row as i32 + 1, false, &[line])?;
+
+    let theme = CONTAINER.theme.read().await;
+
+    buf.add_highlight(lua, theme.popup_ns as i32, "Title", row, 0, -1)?;
+
+    Ok(())
+}
+
+fn buf_is_valid(lua: &Lua, buf: NeoBuffer) -> LuaResult<bool> {
+    let api: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("api")?;
+    let is_valid: LuaFunction = api.get("nvim_buf_is_valid")?;
+
+    is_valid.call(buf.id())
+}
+
+fn preview_name(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    if path.is_dir() {
+        format!("{name}/")
+    } else {
+        name
+    }
+}
+
+impl Job {
+    /// Only one job runs at a time. Callers that consume the selection or the journal check this
+    /// first, and notify when it's busy
+    pub async fn is_busy(lua: &Lua) -> LuaResult<bool> {
+        if CONTAINER.job.read().await.is_none() {
+            return Ok(false);
+        }
+
+        NeoApi::notify(lua, &BUSY_MESSAGE)?;
+        Ok(true)
+    }
+
+    /// Runs the work in the background, a progress popup reports on it until it's done. Returns
+    /// false when another job is still running
+    pub async fn start(lua: &Lua, kind: JobKind) -> LuaResult<bool> {
+        let mut job = CONTAINER.job.write().await;
+
+        if job.is_some() {
+            NeoApi::notify(lua, &BUSY_MESSAGE)?;
+            return Ok(false);
+        }
+
+        let title = kind.title().to_string();
+        let dirs = kind.affected_dirs();
+        let progress = Arc::new(Progress::default());
+        let popup = Self::open_popup(lua, &title)?;
+
+        let job_progress = progress.clone();
+        let handle = RTM.spawn_blocking(move || {
+            let mut summary = kind.execute(&job_progress);
+            let step = JournalStep::new(std::mem::take(&mut summary.operations));
+
+            (summary, step)
+        });
+
+        *job = Some(Self {
+            title,
+            progress,
+            dirs,
+            popup,
+            handle,
+        });
+
+        NeoApi::delay(lua, POLL_INTERVAL_MS, lua.create_async_function(poll_job)?)?;
+
+        Ok(true)
+    }
+
+    fn open_popup(lua: &Lua, title: &str) -> LuaResult<NeoPopup> {
+        let popup_buf = NeoBuffer::create(lua, false, true)?;
+
+        NeoPopup::open(
+            lua,
+            popup_buf,
+            false,
+            WinOptions {
+                relative: PopupRelative::Editor,
+                width: Some(PopupSize::Fixed(36)),
+                height: Some(PopupSize::Fixed(3)),
+                col: Some(PopupSize::Fixed(1000)),
+                row: Some(PopupSize::Percentage(1.)),
+                style: Some(PopupStyle::Minimal),
+                border: PopupBorder::Rounded,
+                anchor: Anchor::NorthWest,
+                focusable: Some(false),
+                title: Some(TextType::Tuples(vec![HLText::new(title, "Question")])),
+                title_pos: PopupAlign::Center,
+                noautocmd: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn update_popup(&self, lua: &Lua) -> LuaResult<()> {
+        let progress = &self.progress;
+        let load = |value: &std::sync::atomic::AtomicU64| value.load(Ordering::Relaxed);
+
+        let lines = [
+            format!(
+                "Files: {}/{}",
+                load(&progress.files_done),
+                load(&progress.files_total)
+            ),
+            format!(
+                "Size:  {}/{}",
+                NeoUtils::human_size(load(&progress.bytes_done)),
+                NeoUtils::human_size(load(&progress.bytes_total))
+            ),
+            if progress.is_cancelled() {
+                "Cancelling...".to_string()
+            } else {
+                "[<C-c>] cancel".to_string()
+            },
+        ];
+
+        self.popup.buf.set_lines(lua, 0, -1, false, &lines)
+    }
+}
+
+async fn poll_job(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut current = CONTAINER.job.write().await;
+
+    let Some(job) = current.as_ref() else {
+        return Ok(());
+    };
+
+    if !job.handle.is_finished() {
+        // The popup may have been closed by hand, the job still has to be polled to the end
+        let _ = job.update_popup(lua);
+
+        return NeoApi::delay(lua, POLL_INTERVAL_MS, lua.create_async_function(poll_job)?);
+    }
+
+    let Some(job) = current.take() else {
+        return Ok(());
+    };
+
+    drop(current);
+
+    let (summary, step) = job.handle.await.map_err(LuaError::external)?;
+
+    let mut journal = CONTAINER.journal.write().await;
+
+    match summary.replay {
+        Some(replay) => journal.finish(replay),
+        None => journal.record(step),
+    }
+
+    drop(journal);
+
+    // Already gone when it was closed by hand
+    let _ = job.popup.win.close(lua, true);
+
+    AppState::refresh_instances(lua, &job.dirs).await?;
+
+    let level = if summary.failed {
+        PopupLevel::Error
+    } else {
+        PopupLevel::Info
+    };
+
+    NeoPopup::notify(
+        lua,
+        PopupNotify {
+            level,
+            title: job.title.trim().to_string(),
+            messages: summary.messages,
+            duration: Duration::from_secs(5),
+        },
+    )
+}
+
+pub async fn cancel_job(_: &Lua, _: ()) -> LuaResult<()> {
+    if let Some(job) = CONTAINER.job.read().await.as_ref() {
+        job.progress.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,482 @@
+use crate::fs_ops::{copy_recursive, move_item, remove_item, CopyOptions, Progress};
+use crate::trash::{Trash, TrashedItem};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// A filesystem change made by traveller, with everything needed to reverse it
+#[derive(Debug, Clone)]
+pub enum FsOperation {
+    Create {
+        path: PathBuf,
+        is_dir: bool,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+        options: CopyOptions,
+    },
+    Trash(TrashedItem),
+}
+
+/// Used to detect whether a file has been changed since it was journaled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+    /// Names, sizes and times of everything inside a directory, when the whole tree matters
+    tree: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+struct JournalEntry {
+    operation: FsOperation,
+    stamp: Option<FileStamp>,
+}
+
+/// The operations of one user action, stamped so later changes on disk are noticed
+#[derive(Debug, Default)]
+pub struct JournalStep(Vec<JournalEntry>);
+
+/// Undo and redo stacks of this session. Every step holds the operations of one user action
+#[derive(Debug, Default)]
+pub struct Journal {
+    undo: Vec<Vec<JournalEntry>>,
+    redo: Vec<Vec<JournalEntry>>,
+}
+
+impl FileStamp {
+    fn read(path: &Path, tree: bool) -> Option<Self> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+
+        let tree = (tree && metadata.is_dir()).then(|| {
+            let mut hasher = DefaultHasher::new();
+            hash_tree(path, &mut hasher);
+            hasher.finish()
+        });
+
+        Some(Self {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+            tree,
+        })
+    }
+}
+
+/// Only needs to hold within the session, like the journal itself
+fn hash_tree(dir: &Path, hasher: &mut DefaultHasher) {
+    let mut entries: Vec<_> = fs::read_dir(dir).into_iter().flatten().flatten().collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+
+        entry.file_name().hash(hasher);
+        metadata.modified().ok().hash(hasher);
+        metadata.len().hash(hasher);
+
+        if metadata.is_dir() {
+            hash_tree(&entry.path(), hasher);
+        }
+    }
+}
+
+impl FsOperation {
+    /// Where the item lives once the operation has been applied
+    fn applied_path(&self) -> Option<&Path> {
+        match self {
+            Self::Create { path, .. } => Some(path),
+            Self::Rename { to, .. } | Self::Copy { to, .. } => Some(to),
+            Self::Trash(item) => Some(&item.trashed),
+        }
+    }
+
+    /// Where the item lives once the operation has been reverted
+    fn reverted_path(&self) -> Option<&Path> {
+        match self {
+            Self::Create { .. } | Self::Copy { .. } => None,
+            Self::Rename { from, .. } => Some(from),
+            Self::Trash(item) => Some(&item.original),
+        }
+    }
+
+    /// Undoing a copy removes the whole tree, so changes inside it count as well
+    fn stamp(&self, path: Option<&Path>) -> Option<FileStamp> {
+        FileStamp::read(path?, matches!(self, Self::Copy { .. }))
+    }
+
+    /// Where the item lives before it's undone, or before it's redone
+    fn current_path(&self, undo: bool) -> Option<&Path> {
+        if undo {
+            self.applied_path()
+        } else {
+            self.reverted_path()
+        }
+    }
+
+    /// Directories whose listing changes by this operation
+    pub fn affected_dirs(&self) -> Vec<PathBuf> {
+        let paths: Vec<&Path> = match self {
+            Self::Create { path, .. } => vec![path],
+            Self::Rename { from, to } | Self::Copy { from, to, .. } => vec![from, to],
+            Self::Trash(item) => vec![&item.original],
+        };
+
+        paths
+            .into_iter()
+            .filter_map(|path| path.parent())
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    fn apply(&mut self, progress: &Progress) -> io::Result<()> {
+        match self {
+            Self::Create { path, is_dir } => {
+                ensure_free(path)?;
+
+                if *is_dir {
+                    fs::create_dir(path)
+                } else {
+                    fs::File::create(path).map(|_| ())
+                }
+            }
+            Self::Rename { from, to } => {
+                ensure_free(to)?;
+                move_item(from, to, progress).map(|_| ())
+            }
+            Self::Copy { from, to, options } => {
+                ensure_free(to)?;
+                let copied = copy_recursive(from, to, *options, progress).map(|_| ());
+
+                // Don't leave half copied trees behind
+                if copied.is_err() {
+                    let _ = remove_item(to, &Progress::default());
+                }
+
+                copied.map_err(io::Error::from)
+            }
+            Self::Trash(item) => {
+                *item = Trash::put(&item.original)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn revert(&mut self, progress: &Progress) -> io::Result<()> {
+        match self {
+            Self::Create { path, is_dir: true } => fs::remove_dir(path),
+            Self::Create {
+                path,
+                is_dir: false,
+            } => fs::remove_file(path),
+            Self::Rename { from, to } => {
+                ensure_free(from)?;
+                move_item(to, from, progress).map(|_| ())
+            }
+            Self::Copy { to, .. } => remove_item(to, progress),
+            Self::Trash(item) => Trash::restore(item),
+        }
+    }
+}
+
+fn ensure_free(path: &Path) -> io::Result<()> {
+    if path.symlink_metadata().is_ok() {
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", path.display()),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+impl JournalStep {
+    /// Reads whole copied trees, so it's made in the background by the job that did the work
+    pub fn new(operations: Vec<FsOperation>) -> Self {
+        let entries = operations
+            .into_iter()
+            .map(|operation| JournalEntry {
+                stamp: operation.stamp(operation.applied_path()),
+                operation,
+            })
+            .collect();
+
+        Self(entries)
+    }
+}
+
+impl JournalEntry {
+    fn verify(&self, undo: bool) -> io::Result<()> {
+        let Some(path) = self.operation.current_path(undo) else {
+            return Ok(());
+        };
+
+        if self.stamp.is_none() || self.stamp != self.operation.stamp(Some(path)) {
+            return Err(io::Error::other(format!(
+                "{} has changed on disk since the operation",
+                path.display()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Journal {
+    /// Records the operations of one user action, which invalidates the redo stack
+    pub fn record(&mut self, step: JournalStep) {
+        if step.0.is_empty() {
+            return;
+        }
+
+        self.undo.push(step.0);
+        self.redo.clear();
+    }
+
+    /// Takes the last step off the undo or redo stack, `finish` puts it back once it has run
+    pub fn take(&mut self, undo: bool) -> io::Result<Replay> {
+        let (stack, action) = if undo {
+            (&mut self.undo, "undo")
+        } else {
+            (&mut self.redo, "redo")
+        };
+
+        let step = stack
+            .pop()
+            .ok_or_else(|| io::Error::other(format!("Nothing to {action}")))?;
+
+        Ok(Replay {
+            undo,
+            step,
+            done: 0,
+        })
+    }
+
+    /// An undone step can be redone and the other way around. When it failed halfway it's split,
+    /// what's left stays where it was so it can be retried
+    pub fn finish(&mut self, replay: Replay) {
+        let Replay {
+            undo,
+            mut step,
+            done,
+        } = replay;
+
+        // Undone back to front, redone front to back
+        let (left, finished) = if undo {
+            let finished = step.split_off(step.len() - done);
+            (step, finished)
+        } else {
+            let left = step.split_off(done);
+            (left, step)
+        };
+
+        let (own, other) = if undo {
+            (&mut self.undo, &mut self.redo)
+        } else {
+            (&mut self.redo, &mut self.undo)
+        };
+
+        if !finished.is_empty() {
+            other.push(finished);
+        }
+
+        if !left.is_empty() {
+            own.push(left);
+        }
+    }
+}
+
+/// A step of the journal that's undone or redone in the background
+#[derive(Debug)]
+pub struct Replay {
+    pub undo: bool,
+    step: Vec<JournalEntry>,
+    /// Operations that have been undone or redone
+    done: usize,
+}
+
+impl Replay {
+    /// Directories whose listing changes by this step
+    pub fn affected_dirs(&self) -> Vec<PathBuf> {
+        self.step
+            .iter()
+            .flat_map(|entry| entry.operation.affected_dirs())
+            .collect()
+    }
+
+    pub fn operation_count(&self) -> usize {
+        self.step.len()
+    }
+
+    pub fn done_count(&self) -> usize {
+        self.done
+    }
+
+    /// Refused as a whole when one of the items has changed on disk since the step was made
+    pub fn run(&mut self, progress: &Progress) -> io::Result<()> {
+        let undo = self.undo;
+
+        self.step.iter().try_for_each(|entry| entry.verify(undo))?;
+
+        // Undone back to front, so later operations on the same items are reverted first
+        let mut entries: Vec<_> = self.step.iter_mut().collect();
+
+        if undo {
+            entries.reverse();
+        }
+
+        let totals: Vec<_> = entries
+            .iter()
+            .map(|entry| match entry.operation.current_path(undo) {
+                Some(path) => progress.scan(path),
+                None => (0, 0),
+            })
+            .collect();
+
+        let mut done = (0, 0);
+
+        for (entry, (files, bytes)) in entries.into_iter().zip(totals) {
+            progress.check_cancelled()?;
+
+            if undo {
+                entry.operation.revert(progress)?;
+            } else {
+                entry.operation.apply(progress)?;
+            }
+
+            entry.stamp = entry.operation.stamp(entry.operation.current_path(!undo));
+            self.done += 1;
+
+            done = (done.0 + files, done.1 + bytes);
+            progress.settle(done.0, done.1);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FsOperation, Journal, JournalStep};
+    use crate::fs_ops::{copy_recursive, CopyOptions, Progress};
+    use crate::test_utils::TempDir;
+    use std::{fs, io, path::PathBuf};
+
+    /// Undoes or redoes the last step the way the job does
+    fn replay(journal: &mut Journal, undo: bool) -> io::Result<()> {
+        let mut replay = journal.take(undo)?;
+        let result = replay.run(&Progress::default());
+        journal.finish(replay);
+
+        result
+    }
+
+    /// `a.txt` renamed to `b.txt`, with the rename in the journal
+    fn renamed(root: &TempDir) -> (Journal, PathBuf, PathBuf) {
+        let from = root.join("a.txt");
+        let to = root.join("b.txt");
+        fs::write(&from, "content").unwrap();
+        fs::rename(&from, &to).unwrap();
+
+        let mut journal = Journal::default();
+        journal.record(JournalStep::new(vec![FsOperation::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        }]));
+
+        (journal, from, to)
+    }
+
+    #[test]
+    pub fn undo_redo_rename() {
+        let root = TempDir::new();
+        let (mut journal, from, to) = renamed(&root);
+
+        replay(&mut journal, true).unwrap();
+        assert!(from.is_file() && !to.exists());
+
+        replay(&mut journal, false).unwrap();
+        assert!(!from.exists() && to.is_file());
+    }
+
+    #[test]
+    pub fn undo_changed_item() {
+        let root = TempDir::new();
+        let (mut journal, _, to) = renamed(&root);
+
+        // Changed on disk since the rename, so undo is refused
+        fs::write(&to, "changed content").unwrap();
+        assert!(replay(&mut journal, true).is_err());
+        assert!(to.is_file());
+    }
+
+    #[test]
+    pub fn undo_changed_copy() {
+        let root = TempDir::new();
+        let (from, to) = (root.join("from"), root.join("to"));
+        fs::create_dir_all(from.join("nested")).unwrap();
+        fs::write(from.join("nested/file.txt"), "content").unwrap();
+
+        let options = CopyOptions::default();
+        copy_recursive(&from, &to, options, &Progress::default()).unwrap();
+
+        let mut journal = Journal::default();
+        journal.record(JournalStep::new(vec![FsOperation::Copy {
+            from,
+            to: to.clone(),
+            options,
+        }]));
+
+        // Only the content changed, undo would remove it
+        fs::write(to.join("nested/file.txt"), "changed content").unwrap();
+        assert!(replay(&mut journal, true).is_err());
+        assert!(to.join("nested/file.txt").is_file());
+    }
+
+    #[test]
+    pub fn partially_undone() {
+        let root = TempDir::new();
+        let names = ["a.txt", "b.txt", "c.txt", "d.txt"].map(|name| root.join(name));
+        let [a, b, c, d] = &names;
+
+        fs::write(b, "").unwrap();
+        fs::write(d, "").unwrap();
+
+        let mut journal = Journal::default();
+        journal.record(JournalStep::new(vec![
+            FsOperation::Rename {
+                from: a.clone(),
+                to: b.clone(),
+            },
+            FsOperation::Rename {
+                from: c.clone(),
+                to: d.clone(),
+            },
+        ]));
+
+        // Undone back to front, so the second rename is reverted before the first one fails
+        fs::write(a, "").unwrap();
+        assert!(replay(&mut journal, true).is_err());
+        assert!(c.is_file() && !d.exists());
+
+        fs::remove_file(a).unwrap();
+        replay(&mut journal, true).unwrap();
+        assert!(a.is_file() && !b.exists());
+
+        replay(&mut journal, false).unwrap();
+        replay(&mut journal, false).unwrap();
+        assert!(b.is_file() && d.is_file() && !a.exists() && !c.exists());
+        assert!(replay(&mut journal, false).is_err());
+    }
+}
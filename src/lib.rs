@@ -1,24 +1,54 @@
+use config::Config;
+use history::History;
+use journal::Journal;
 use neo_api_rs::mlua;
 use neo_api_rs::mlua::prelude::*;
 use neo_api_rs::*;
 use once_cell::sync::Lazy;
-use state::AppState;
+use state::{open_path, AppState};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use theme::Theme;
 use utils::NeoUtils;
 
+mod config;
+mod details;
+mod dir_size;
+mod edit;
+mod error;
+mod file_kind;
+mod filter;
+mod fs_ops;
+mod git_status;
+mod history;
+mod icons;
+mod job;
+mod journal;
+mod links;
+mod ls_colors;
+mod miller;
+mod paste;
 mod popup;
+mod preview;
+mod rename;
+mod sort;
 mod state;
+#[cfg(test)]
+mod test_utils;
 mod theme;
+mod trash;
+mod tree;
 mod utils;
 
 static CONTAINER: Lazy<AppState> = Lazy::new(|| AppState {
-    history_dir: PathBuf::new().into(),
+    history: History::default().into(),
     theme: Theme::default().into(),
     active_buf: 0.into(),
     instances: HashMap::new().into(),
     selection: HashMap::new().into(),
+    journal: Journal::default().into(),
+    job: None.into(),
+    config: Config::default().into(),
 });
 
 #[mlua::lua_module]
@@ -31,6 +61,8 @@ fn nvim_traveller_rs(lua: &Lua) -> LuaResult<LuaTable> {
 
     let module = lua.create_table()?;
 
+    module.set("setup", lua.create_async_function(setup)?)?;
+
     module.set(
         "open_navigation",
         lua.create_async_function(open_navigation)?,
@@ -48,11 +80,18 @@ fn nvim_traveller_rs(lua: &Lua) -> LuaResult<LuaTable> {
     Ok(module)
 }
 
+async fn setup(_: &Lua, opts: LuaTable<'_>) -> LuaResult<()> {
+    CONTAINER.config.write().await.update(opts)
+}
+
 async fn open_navigation(lua: &Lua, _: ()) -> LuaResult<()> {
     let mut started_from = NeoApi::get_filepath(lua)?;
 
     if !started_from.is_file() {
-        started_from = started_from.parent().unwrap().to_path_buf();
+        started_from = match started_from.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => NeoApi::get_cwd(lua)?,
+        };
     }
 
     if let Err(err) = AppState::open_navigation(lua, started_from).await {
@@ -67,9 +106,11 @@ pub struct TravellerFuzzy(FuzzySearch);
 impl FuzzyConfig for TravellerFuzzy {
     fn cwd(&self, lua: &Lua) -> PathBuf {
         match self.0 {
-            FuzzySearch::Files => NeoApi::get_cwd(lua).unwrap(),
+            FuzzySearch::Files => {
+                NeoApi::get_cwd(lua).unwrap_or_else(|_| NeoUtils::home_directory())
+            }
             FuzzySearch::GitFiles => {
-                let cwd = NeoApi::get_cwd(lua).unwrap();
+                let cwd = NeoApi::get_cwd(lua).unwrap_or_else(|_| NeoUtils::home_directory());
                 if let Some(git_root) = NeoUtils::git_root(&cwd) {
                     git_root
                 } else {
@@ -92,7 +133,7 @@ impl FuzzyConfig for TravellerFuzzy {
                 }
             }),
             FuzzySearch::Files | FuzzySearch::GitFiles => {
-                let _ = NeoApi::open_file(lua, open_in, selected.to_str().unwrap());
+                let _ = open_path(lua, open_in, &selected);
             }
         }
     }
@@ -0,0 +1,109 @@
+use crate::icons::set_hl;
+use neo_api_rs::mlua::prelude::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const BROKEN_LINK_HL: &str = "TravellerBrokenLink";
+pub const EXTERNAL_LINK_HL: &str = "TravellerExternalLink";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Valid,
+    Broken,
+    /// Resolves to a path outside of the git root of the listing
+    External,
+}
+
+/// Where a symlink of the listing points to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymlinkTarget {
+    /// As written in the link, so relative targets stay relative
+    pub target: PathBuf,
+    pub state: LinkState,
+}
+
+impl SymlinkTarget {
+    /// None if the path isn't a symlink
+    pub fn read(path: &Path, git_root: Option<&Path>) -> Option<Self> {
+        let target = fs::read_link(path).ok()?;
+
+        let state = match fs::canonicalize(path) {
+            Err(_) => LinkState::Broken,
+            Ok(resolved) if git_root.is_some_and(|root| !resolved.starts_with(root)) => {
+                LinkState::External
+            }
+            Ok(_) => LinkState::Valid,
+        };
+
+        Some(Self { target, state })
+    }
+
+    pub fn hl_group(&self) -> Option<&'static str> {
+        match self.state {
+            LinkState::Valid => None,
+            LinkState::Broken => Some(BROKEN_LINK_HL),
+            LinkState::External => Some(EXTERNAL_LINK_HL),
+        }
+    }
+}
+
+/// Links the groups with `default`, so colour schemes and users can override them
+pub fn define_link_highlights(lua: &Lua) -> LuaResult<()> {
+    for (name, link) in [
+        (BROKEN_LINK_HL, "ErrorMsg"),
+        (EXTERNAL_LINK_HL, "WarningMsg"),
+    ] {
+        let opts = lua.create_table()?;
+        opts.set("link", link)?;
+        opts.set("default", true)?;
+
+        set_hl(lua, name, opts)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LinkState, SymlinkTarget};
+    use crate::test_utils::TempDir;
+    use std::{fs, os::unix::fs::symlink, path::PathBuf};
+
+    /// Repository with a valid, a broken and an external link
+    fn linked_repo(root: &TempDir) -> PathBuf {
+        fs::create_dir_all(root.join("repo/src")).unwrap();
+
+        let repo = fs::canonicalize(root.join("repo")).unwrap();
+        symlink("src", repo.join("valid")).unwrap();
+        symlink("missing", repo.join("broken")).unwrap();
+        symlink(root, repo.join("external")).unwrap();
+
+        repo
+    }
+
+    #[test]
+    pub fn symlink_targets() {
+        let root = TempDir::new();
+        let repo = linked_repo(&root);
+
+        let read = |name: &str| SymlinkTarget::read(&repo.join(name), Some(&repo)).unwrap();
+
+        assert_eq!(PathBuf::from("src"), read("valid").target);
+        assert_eq!(LinkState::Valid, read("valid").state);
+        assert_eq!(LinkState::Broken, read("broken").state);
+        assert_eq!(LinkState::External, read("external").state);
+    }
+
+    #[test]
+    pub fn symlinks_outside_repository() {
+        let root = TempDir::new();
+        let repo = linked_repo(&root);
+
+        // Outside of a git repository nothing is external
+        let external = SymlinkTarget::read(&repo.join("external"), None).unwrap();
+        assert_eq!(LinkState::Valid, external.state);
+        assert!(SymlinkTarget::read(&repo.join("src"), None).is_none());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::icons::set_hl;
+use neo_api_rs::mlua::prelude::*;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+pub static LS_COLORS: Lazy<LsColors> =
+    Lazy::new(|| LsColors::parse(&std::env::var("LS_COLORS").unwrap_or_default()));
+
+const ANSI_NAMES: [&str; 16] = [
+    "Black",
+    "DarkRed",
+    "DarkGreen",
+    "DarkYellow",
+    "DarkBlue",
+    "DarkMagenta",
+    "DarkCyan",
+    "LightGray",
+    "DarkGray",
+    "Red",
+    "Green",
+    "Yellow",
+    "Blue",
+    "Magenta",
+    "Cyan",
+    "White",
+];
+
+/// Highlight of a `LS_COLORS` rule, made from its SGR codes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LsStyle {
+    pub fg: Option<String>,
+    pub ctermfg: Option<u8>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+/// The rules of `LS_COLORS`, each gets a `TravellerLs<index>` highlight group
+#[derive(Debug, Default)]
+pub struct LsColors {
+    styles: Vec<LsStyle>,
+    /// `di`, `ln`, `ex`, ... by their style index
+    types: HashMap<String, usize>,
+    /// `*.tar` like rules, matched against the end of the name
+    suffixes: Vec<(String, usize)>,
+}
+
+impl LsColors {
+    pub fn parse(ls_colors: &str) -> Self {
+        let mut colors = Self::default();
+
+        for rule in ls_colors.split(':') {
+            let Some((key, codes)) = rule.split_once('=') else {
+                continue;
+            };
+
+            let Some(style) = LsStyle::parse(codes) else {
+                continue;
+            };
+
+            let index = colors.styles.len();
+            colors.styles.push(style);
+
+            match key.strip_prefix('*') {
+                Some(suffix) => colors.suffixes.push((suffix.to_lowercase(), index)),
+                None => {
+                    colors.types.insert(key.to_string(), index);
+                }
+            }
+        }
+
+        // Longest suffix wins, e.g. `.tar.gz` over `.gz`
+        colors
+            .suffixes
+            .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+
+        colors
+    }
+
+    pub fn define_highlights(&self, lua: &Lua) -> LuaResult<()> {
+        for (index, style) in self.styles.iter().enumerate() {
+            let opts = lua.create_table()?;
+            opts.set("fg", style.fg.clone())?;
+            opts.set("ctermfg", style.ctermfg)?;
+            opts.set("bold", style.bold)?;
+            opts.set("italic", style.italic)?;
+            opts.set("underline", style.underline)?;
+
+            set_hl(lua, &group_name(index), opts)?;
+        }
+
+        Ok(())
+    }
+
+    /// Highlight group the way `ls` would colour the item
+    pub fn hl_group(&self, path: &Path) -> Option<String> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        let file_type = metadata.file_type();
+
+        let key = if file_type.is_symlink() {
+            if path.exists() {
+                "ln"
+            } else {
+                "or"
+            }
+        } else if file_type.is_dir() {
+            "di"
+        } else if file_type.is_fifo() {
+            "pi"
+        } else if file_type.is_socket() {
+            "so"
+        } else if file_type.is_block_device() {
+            "bd"
+        } else if file_type.is_char_device() {
+            "cd"
+        } else if metadata.permissions().mode() & 0o111 != 0 {
+            "ex"
+        } else {
+            let name = path.file_name()?.to_string_lossy().to_lowercase();
+
+            let suffix = self
+                .suffixes
+                .iter()
+                .find(|(suffix, _)| name.ends_with(suffix.as_str()));
+
+            if let Some((_, index)) = suffix {
+                return Some(group_name(*index));
+            }
+
+            "fi"
+        };
+
+        self.types.get(key).map(|index| group_name(*index))
+    }
+}
+
+impl LsStyle {
+    /// Parses SGR codes like `01;38;5;208`. None if they don't change the look
+    fn parse(codes: &str) -> Option<Self> {
+        let mut style = Self::default();
+        let mut codes = codes.split(';').map(|code| code.parse::<u8>().ok());
+
+        while let Some(code) = codes.next() {
+            match code? {
+                1 => style.bold = true,
+                3 => style.italic = true,
+                4 => style.underline = true,
+                code @ 30..=37 => style.set_ansi(code - 30),
+                code @ 90..=97 => style.set_ansi(code - 90 + 8),
+                38 => match codes.next().flatten()? {
+                    5 => {
+                        let color = codes.next().flatten()?;
+                        style.ctermfg = Some(color);
+                        style.fg = Some(xterm_color(color));
+                    }
+                    2 => {
+                        let mut rgb = [0; 3];
+
+                        for channel in rgb.iter_mut() {
+                            *channel = codes.next().flatten()?;
+                        }
+
+                        style.fg = Some(format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2]));
+                    }
+                    _ => return None,
+                },
+                // Resets and backgrounds are left to the colour scheme
+                _ => {}
+            }
+        }
+
+        (style != Self::default()).then_some(style)
+    }
+
+    fn set_ansi(&mut self, color: u8) {
+        self.ctermfg = Some(color);
+        self.fg = Some(ANSI_NAMES[color as usize].to_string());
+    }
+}
+
+fn group_name(index: usize) -> String {
+    format!("TravellerLs{index}")
+}
+
+/// Hex of a color of the xterm 256 color palette
+fn xterm_color(color: u8) -> String {
+    match color {
+        0..=15 => ANSI_NAMES[color as usize].to_string(),
+        16..=231 => {
+            let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+            let index = color - 16;
+
+            format!(
+                "#{:02x}{:02x}{:02x}",
+                level(index / 36),
+                level(index / 6 % 6),
+                level(index % 6)
+            )
+        }
+        _ => {
+            let gray = 8 + (color - 232) * 10;
+            format!("#{gray:02x}{gray:02x}{gray:02x}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LsColors, LsStyle};
+    use crate::test_utils::TempDir;
+    use std::fs;
+
+    const LS_COLORS: &str = "rs=0:di=01;34:ex=38;5;208:*.tar.gz=38;2;255;0;16:*.gz=31";
+
+    #[test]
+    pub fn parse_ls_colors() {
+        let colors = LsColors::parse(LS_COLORS);
+
+        assert_eq!(4, colors.styles.len());
+        assert_eq!(
+            LsStyle {
+                fg: Some("DarkBlue".to_string()),
+                ctermfg: Some(4),
+                bold: true,
+                ..Default::default()
+            },
+            colors.styles[colors.types["di"]]
+        );
+        assert_eq!(
+            Some("#ff8700"),
+            colors.styles[colors.types["ex"]].fg.as_deref()
+        );
+    }
+
+    #[test]
+    pub fn ls_colors_groups() {
+        let colors = LsColors::parse(LS_COLORS);
+
+        let root = TempDir::new();
+        fs::write(root.join("backup.TAR.GZ"), "").unwrap();
+        fs::write(root.join("notes.gz"), "").unwrap();
+
+        assert_eq!(Some("TravellerLs0".to_string()), colors.hl_group(&root));
+        assert_eq!(
+            Some("TravellerLs2".to_string()),
+            colors.hl_group(&root.join("backup.TAR.GZ"))
+        );
+        assert_eq!(
+            Some("TravellerLs3".to_string()),
+            colors.hl_group(&root.join("notes.gz"))
+        );
+    }
+}
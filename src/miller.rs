@@ -0,0 +1,169 @@
+use crate::state::{nav_items, AppInstance, AppState};
+use crate::utils::NeoUtils;
+use crate::CONTAINER;
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::*;
+use std::path::Path;
+
+/// Part of the navigation window taken by the parent column, the preview is the child column
+const PARENT_WIDTH: f32 = 0.2;
+
+impl AppInstance {
+    /// The listing shows the preview on its right, so it follows the cursor
+    pub fn shows_preview(&self) -> bool {
+        self.show_preview || self.miller
+    }
+
+    fn create_parent_pane(&mut self, lua: &Lua) -> LuaResult<()> {
+        let pane_buf = NeoBuffer::create(lua, false, true)?;
+
+        let pane = NeoPopup::open(
+            lua,
+            pane_buf,
+            false,
+            WinOptions {
+                relative: PopupRelative::Win,
+                win: Some(self.win.id()),
+                width: Some(PopupSize::Percentage(PARENT_WIDTH)),
+                height: Some(PopupSize::Percentage(0.8)),
+                col: Some(PopupSize::Fixed(0)),
+                row: Some(PopupSize::Fixed(0)),
+                style: Some(PopupStyle::Minimal),
+                border: PopupBorder::Rounded,
+                anchor: Anchor::NorthWest,
+                focusable: Some(false),
+                title: Some(TextType::Tuples(vec![HLText::new(" Parent ", "Question")])),
+                title_pos: PopupAlign::Left,
+                noautocmd: true,
+                ..Default::default()
+            },
+        )?;
+
+        // Blank status column as wide as the pane and its border, the listing moves right of it
+        let width = win_width(lua, &self.win)?;
+        let padding = (width as f32 * PARENT_WIDTH).round() as usize + 3;
+        self.win
+            .set_option_value(lua, "statuscolumn", " ".repeat(padding))?;
+
+        self.parent_pane = Some(pane);
+
+        Ok(())
+    }
+
+    pub fn close_parent_pane(&mut self, lua: &Lua) -> LuaResult<()> {
+        if let Some(pane) = self.parent_pane.take() {
+            pane.win.close(lua, true)?;
+            self.win.set_option_value(lua, "statuscolumn", "")?;
+        }
+
+        Ok(())
+    }
+
+    /// Opens the left column if it was closed by leaving the buffer, the preview is the right one
+    pub async fn open_parent_pane(&mut self, lua: &Lua) -> LuaResult<()> {
+        if !self.miller || self.parent_pane.is_some() {
+            return Ok(());
+        }
+
+        self.create_parent_pane(lua)?;
+        self.update_parent_pane(lua).await
+    }
+
+    /// Lists the parent directory with the current one highlighted. Only updates an open pane,
+    /// so instances that aren't shown can be reloaded
+    pub async fn update_parent_pane(&self, lua: &Lua) -> LuaResult<()> {
+        let Some(pane) = &self.parent_pane else {
+            return Ok(());
+        };
+
+        let config = CONTAINER.config.read().await;
+
+        let names = match self.cwd.parent() {
+            Some(parent) => nav_items(
+                parent,
+                0,
+                self.show_hidden,
+                self.sort,
+                config.sort_case_insensitive,
+            )
+            .map(|items| items.into_iter().map(|item| item.name).collect())
+            .unwrap_or_default(),
+            None => vec![],
+        };
+
+        drop(config);
+
+        pane.buf.set_lines(lua, 0, -1, false, &names)?;
+
+        let theme = CONTAINER.theme.read().await;
+        let ns = theme.popup_ns as i32;
+        pane.buf.clear_namespace(lua, ns, 0, -1)?;
+
+        for (i, name) in names.iter().enumerate() {
+            if name.ends_with('/') {
+                pane.buf.add_highlight(lua, ns, "Directory", i, 0, -1)?;
+            }
+        }
+
+        if let Some(row) = current_row(&self.cwd, &names) {
+            pane.buf.add_highlight(lua, ns, "Visual", row, 0, -1)?;
+            pane.win
+                .set_cursor(lua, WinCursor::from_zero_indexed(row as u32, 0))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Row of the directory in the listing of its parent
+fn current_row(cwd: &Path, names: &[String]) -> Option<usize> {
+    let name = format!("{}/", NeoUtils::escape_name(cwd.file_name()?));
+    names.iter().position(|item| *item == name)
+}
+
+fn win_width(lua: &Lua, win: &NeoWindow) -> LuaResult<u32> {
+    let api: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("api")?;
+    let get_width: LuaFunction = api.get("nvim_win_get_width")?;
+
+    get_width.call(win.id())
+}
+
+/// Ranger like columns: the parent directory, the listing and the item under the cursor
+pub async fn toggle_miller(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    instance.miller = !instance.miller;
+
+    if instance.miller {
+        instance.open_parent_pane(lua).await?;
+        return instance.update_preview(lua).await;
+    }
+
+    instance.close_parent_pane(lua)?;
+
+    if !instance.shows_preview() {
+        instance.close_preview(lua)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::current_row;
+    use std::path::Path;
+
+    #[test]
+    pub fn parent_column_row() {
+        let names = vec![
+            "docs/".to_string(),
+            "src/".to_string(),
+            "src.rs".to_string(),
+        ];
+
+        assert_eq!(Some(1), current_row(Path::new("/project/src"), &names));
+        assert_eq!(None, current_row(Path::new("/project/target"), &names));
+        assert_eq!(None, current_row(Path::new("/"), &names));
+    }
+}
@@ -0,0 +1,412 @@
+use crate::fs_ops::{copy_recursive, move_item, remove_item, CopyOptions, MoveOutcome, Progress};
+use crate::journal::FsOperation;
+use crate::state::{delete_item, SelectionData};
+use crate::utils::NeoUtils;
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Overwrite,
+    Skip,
+    KeepBoth,
+}
+
+#[derive(Debug, Clone)]
+pub struct PasteItem {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// Target already exists, or another item of the selection is pasted onto it
+    pub conflict: bool,
+    /// Shares the target with an item of the selection that is pasted before it
+    pub duplicate: bool,
+    pub resolution: Option<Resolution>,
+}
+
+/// Everything that needs to happen to paste the selection into a directory
+#[derive(Debug, Clone)]
+pub struct PastePlan {
+    pub copy: bool,
+    pub options: CopyOptions,
+    pub items: Vec<PasteItem>,
+}
+
+#[derive(Debug, Default)]
+pub struct PasteSummary {
+    pub pasted: usize,
+    pub overwritten: usize,
+    pub kept_both: usize,
+    pub skipped: usize,
+    pub failed: Vec<String>,
+    /// Sockets and devices inside copied trees
+    pub left_out: Vec<PathBuf>,
+    /// Moves that needed a copy because they crossed filesystems
+    pub moved_across: Vec<String>,
+    pub cancelled: bool,
+    pub operations: Vec<FsOperation>,
+}
+
+impl PastePlan {
+    pub fn new(selection: &SelectionData, target_dir: &Path, copy: bool) -> Self {
+        let mut sources: Vec<_> = selection
+            .iter()
+            .flat_map(|(cwd, names)| {
+                names.iter().map(move |name| {
+                    let source = NeoUtils::item_path(cwd, name);
+                    let target = NeoUtils::item_path(target_dir, name);
+                    (source, target)
+                })
+            })
+            .collect();
+
+        // The same name from different directories, the first one by path is pasted as it is
+        sources.sort();
+        let mut planned = HashSet::new();
+        let mut items = vec![];
+
+        for (source, target) in sources {
+            let exists = target.symlink_metadata().is_ok();
+            let duplicate = !planned.insert(target.clone());
+
+            // Pasting onto itself: a copy becomes a duplicate, a move does nothing
+            let resolution = match (exists && source == target, copy) {
+                (true, true) => Some(Resolution::KeepBoth),
+                (true, false) => Some(Resolution::Skip),
+                _ => None,
+            };
+
+            items.push(PasteItem {
+                source,
+                target,
+                conflict: exists || duplicate,
+                duplicate,
+                resolution,
+            });
+        }
+
+        // Stable, so items with the same target keep their order
+        items.sort_by(|a, b| a.target.cmp(&b.target));
+
+        Self {
+            copy,
+            options: CopyOptions::default(),
+            items,
+        }
+    }
+
+    /// First conflict that still needs a resolution
+    pub fn next_conflict(&self) -> Option<&PasteItem> {
+        self.items
+            .iter()
+            .find(|item| item.conflict && item.resolution.is_none())
+    }
+
+    pub fn conflict_count(&self) -> usize {
+        self.items.iter().filter(|item| item.conflict).count()
+    }
+
+    /// Resolves the next conflict, or every remaining one when `apply_to_all` is set
+    pub fn resolve(&mut self, resolution: Resolution, apply_to_all: bool) {
+        let unresolved = self
+            .items
+            .iter_mut()
+            .filter(|item| item.conflict && item.resolution.is_none());
+
+        for item in unresolved {
+            item.resolution = Some(resolution);
+
+            if !apply_to_all {
+                break;
+            }
+        }
+    }
+
+    pub fn execute(self, progress: &Progress) -> PasteSummary {
+        let mut summary = PasteSummary::default();
+
+        let totals: Vec<_> = self
+            .items
+            .iter()
+            .map(|item| progress.scan(&item.source))
+            .collect();
+
+        let mut done = (0, 0);
+
+        for (item, (files, bytes)) in self.items.into_iter().zip(totals) {
+            done = (done.0 + files, done.1 + bytes);
+
+            if progress.is_cancelled() {
+                summary.cancelled = true;
+                summary.skipped += 1;
+                continue;
+            }
+
+            let mut target = item.target;
+            let resolution = item.resolution.filter(|_| item.conflict);
+
+            match resolution {
+                // A duplicate whose earlier item wasn't pasted has nothing to overwrite
+                Some(Resolution::Overwrite) if target.symlink_metadata().is_ok() => {
+                    match delete_item(&target, false, &Progress::default()) {
+                        Ok(operation) => summary.operations.extend(operation),
+                        Err(err) => {
+                            summary.failed.push(format!("{}: {err}", target.display()));
+                            continue;
+                        }
+                    }
+                }
+                Some(Resolution::Overwrite) => {}
+                // Looked up now, so it's unique among the items pasted before it as well
+                Some(Resolution::KeepBoth) => target = unique_target(&target),
+                Some(Resolution::Skip) | None if item.conflict => {
+                    summary.skipped += 1;
+                    progress.settle(done.0, done.1);
+                    continue;
+                }
+                Some(Resolution::Skip) | None => {}
+            }
+
+            let result = if self.copy {
+                copy_recursive(&item.source, &target, self.options, progress)
+                    .map_err(io::Error::from)
+                    .map(|left_out| {
+                        summary.left_out.extend(left_out);
+
+                        FsOperation::Copy {
+                            from: item.source.clone(),
+                            to: target.clone(),
+                            options: self.options,
+                        }
+                    })
+            } else {
+                move_item(&item.source, &target, progress).map(|outcome| {
+                    if outcome == MoveOutcome::CopiedAcross {
+                        summary.moved_across.push(format!(
+                            "{} -> {}",
+                            item.source.display(),
+                            target.display()
+                        ));
+                    }
+
+                    FsOperation::Rename {
+                        from: item.source.clone(),
+                        to: target.clone(),
+                    }
+                })
+            };
+
+            match result {
+                Ok(operation) => {
+                    summary.operations.push(operation);
+                    summary.pasted += 1;
+
+                    match resolution {
+                        Some(Resolution::Overwrite) => summary.overwritten += 1,
+                        Some(Resolution::KeepBoth) => summary.kept_both += 1,
+                        Some(Resolution::Skip) | None => {}
+                    }
+                }
+                Err(err) => {
+                    // Don't leave half copied trees behind
+                    if self.copy && err.kind() != io::ErrorKind::AlreadyExists {
+                        let _ = remove_item(&target, &Progress::default());
+                    }
+
+                    summary.cancelled |= err.kind() == io::ErrorKind::Interrupted;
+                    summary
+                        .failed
+                        .push(format!("{}: {err}", item.source.display()));
+                }
+            }
+
+            progress.settle(done.0, done.1);
+        }
+
+        summary
+    }
+}
+
+impl PasteSummary {
+    pub fn messages(&self) -> Vec<String> {
+        let mut messages = vec![format!(
+            "Pasted: {}, overwritten: {}, kept both: {}, skipped: {}",
+            self.pasted, self.overwritten, self.kept_both, self.skipped
+        )];
+
+        if self.cancelled {
+            messages[0].push_str(" (cancelled)");
+        }
+
+        messages.extend(
+            self.moved_across
+                .iter()
+                .map(|moved| format!("Copied across filesystems and removed source: {moved}")),
+        );
+        messages.extend(self.left_out.iter().map(|path| {
+            format!(
+                "Left out {}, sockets and devices aren't copied",
+                path.display()
+            )
+        }));
+        messages.extend(self.failed.iter().map(|failed| format!("Failed {failed}")));
+        messages
+    }
+}
+
+/// Finds a free `name (1).ext` style path next to the target
+pub fn unique_target(target: &Path) -> PathBuf {
+    let parent = target.parent().unwrap_or(Path::new(""));
+    // Bytes, so names that aren't valid UTF-8 keep them
+    let file_name = target.file_name().unwrap_or_default().as_bytes();
+
+    let (stem, ext) = match file_name.iter().rposition(|byte| *byte == b'.') {
+        Some(idx) if idx > 0 => file_name.split_at(idx),
+        _ => (file_name, &[][..]),
+    };
+
+    // Continue counting from an existing "name (n)"
+    let (stem, mut count) = stem
+        .strip_suffix(b")")
+        .and_then(|rest| {
+            let open = rest.windows(2).rposition(|pair| pair == b" (")?;
+            let count = std::str::from_utf8(&rest[open + 2..])
+                .ok()?
+                .parse::<usize>()
+                .ok()?;
+            Some((&rest[..open], count))
+        })
+        .unwrap_or((stem, 0));
+
+    loop {
+        count += 1;
+
+        let name = [stem, format!(" ({count})").as_bytes(), ext].concat();
+        let candidate = parent.join(OsStr::from_bytes(&name));
+
+        if candidate.symlink_metadata().is_err() {
+            return candidate;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{unique_target, PastePlan, Resolution};
+    use crate::fs_ops::Progress;
+    use crate::test_utils::TempDir;
+    use std::{collections::HashMap, fs, path::Path};
+
+    #[test]
+    pub fn unique_target_names() {
+        let dir = Path::new("/nonexistent-traveller-dir");
+
+        assert_eq!(
+            dir.join("photo (1).jpg"),
+            unique_target(&dir.join("photo.jpg"))
+        );
+        assert_eq!(
+            dir.join("photo (3).jpg"),
+            unique_target(&dir.join("photo (2).jpg"))
+        );
+        assert_eq!(dir.join(".bashrc (1)"), unique_target(&dir.join(".bashrc")));
+        assert_eq!(dir.join("css (1)"), unique_target(&dir.join("css/")));
+    }
+
+    #[test]
+    pub fn resolve_conflicts() {
+        let root = TempDir::new();
+        let (from, to) = (root.join("from"), root.join("to"));
+        fs::create_dir_all(&from).unwrap();
+        fs::create_dir_all(&to).unwrap();
+
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(from.join(name), "new").unwrap();
+        }
+
+        fs::write(to.join("a.txt"), "old").unwrap();
+        fs::write(to.join("b.txt"), "old").unwrap();
+
+        let selection = HashMap::from([(
+            from.clone(),
+            ["a.txt", "b.txt", "c.txt"].map(String::from).into(),
+        )]);
+
+        let mut plan = PastePlan::new(&selection, &to, true);
+        assert_eq!(2, plan.conflict_count());
+
+        plan.resolve(Resolution::KeepBoth, false);
+        assert_eq!(to.join("b.txt"), plan.next_conflict().unwrap().target);
+
+        plan.resolve(Resolution::Skip, true);
+        assert!(plan.next_conflict().is_none());
+
+        let summary = plan.execute(&Progress::default());
+        assert_eq!(
+            (2, 1, 1),
+            (summary.pasted, summary.kept_both, summary.skipped)
+        );
+        assert_eq!("old", fs::read_to_string(to.join("a.txt")).unwrap());
+        assert_eq!("new", fs::read_to_string(to.join("a (1).txt")).unwrap());
+        assert_eq!("old", fs::read_to_string(to.join("b.txt")).unwrap());
+        assert_eq!("new", fs::read_to_string(to.join("c.txt")).unwrap());
+    }
+
+    #[test]
+    pub fn same_name_from_different_directories() {
+        let root = TempDir::new();
+        let (one, two, to) = (root.join("one"), root.join("two"), root.join("to"));
+
+        for (dir, content) in [(&one, "one"), (&two, "two"), (&to, "")] {
+            fs::create_dir_all(dir).unwrap();
+
+            if !content.is_empty() {
+                fs::write(dir.join("x.txt"), content).unwrap();
+            }
+        }
+
+        let selection = HashMap::from([
+            (one.clone(), ["x.txt"].map(String::from).into()),
+            (two.clone(), ["x.txt"].map(String::from).into()),
+        ]);
+
+        let mut plan = PastePlan::new(&selection, &to, true);
+        assert_eq!(1, plan.conflict_count());
+
+        let conflict = plan.next_conflict().unwrap();
+        assert!(conflict.duplicate);
+        assert_eq!(two.join("x.txt"), conflict.source);
+
+        plan.resolve(Resolution::KeepBoth, false);
+
+        let summary = plan.execute(&Progress::default());
+        assert_eq!((2, 1), (summary.pasted, summary.kept_both));
+        assert_eq!("one", fs::read_to_string(to.join("x.txt")).unwrap());
+        assert_eq!("two", fs::read_to_string(to.join("x (1).txt")).unwrap());
+    }
+
+    #[test]
+    pub fn failed_paste_isnt_counted() {
+        let root = TempDir::new();
+        let (from, to) = (root.join("from"), root.join("to"));
+        fs::create_dir_all(&from).unwrap();
+        fs::create_dir_all(&to).unwrap();
+        fs::write(to.join("gone.txt"), "old").unwrap();
+
+        // Selected, but removed before the paste
+        let selection = HashMap::from([(from, ["gone.txt"].map(String::from).into())]);
+
+        let mut plan = PastePlan::new(&selection, &to, true);
+        plan.resolve(Resolution::KeepBoth, true);
+
+        let summary = plan.execute(&Progress::default());
+        assert_eq!(
+            (0, 0, 1),
+            (summary.pasted, summary.kept_both, summary.failed.len())
+        );
+    }
+}
@@ -1,28 +1,56 @@
 use crate::{
-    state::{AppInstance, AppState, SelectionData},
+    error::{keymap_fn, TravellerError, TravellerResult},
+    job::{count_preview_totals, Job, JobKind},
+    journal::{FsOperation, JournalStep},
+    paste::{PastePlan, Resolution},
+    rename::RenamePlan,
+    state::{apply_paste_plan, clear_selection, confirm_job, AppInstance, AppState, SelectionData},
+    utils::NeoUtils,
     CONTAINER,
 };
 use neo_api_rs::{
     mlua::prelude::{Lua, LuaResult},
     *,
 };
-use std::{fs, io, path::PathBuf, time::Duration};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 #[derive(Clone)]
 struct DeleteItemsCb {
     file_path: PathBuf,
     popup_win: NeoWindow,
+    permanent: bool,
+}
+
+pub async fn trash_items_popup(lua: &Lua, _: ()) -> LuaResult<()> {
+    open_delete_popup(lua, false).await
 }
 
 pub async fn delete_items_popup(lua: &Lua, _: ()) -> LuaResult<()> {
+    open_delete_popup(lua, true).await
+}
+
+async fn open_delete_popup(lua: &Lua, permanent: bool) -> LuaResult<()> {
     let popup_buf = NeoBuffer::create(lua, false, true)?;
 
     let instances = CONTAINER.instances.read().await;
-    let instance = instances.get(&AppState::active_buf()).unwrap();
+    let instance = AppState::active_instance(&instances)?;
 
-    let filename = instance.get_item(lua)?;
-    let delete_info = format!("Delete: {filename}");
-    let file_path = instance.cwd.join(filename);
+    let item = instance.get_item(lua)?;
+    let filename = item.relative_name(&instance.cwd);
+    let delete_info = if permanent {
+        format!("Delete permanently: {filename}")
+    } else {
+        format!("Move to trash: {filename}")
+    };
+    let file_path = item.path;
 
     let popup_win = NeoPopup::open_win(
         lua,
@@ -56,29 +84,24 @@ pub async fn delete_items_popup(lua: &Lua, _: ()) -> LuaResult<()> {
         Box::new(DeleteItemsCb {
             file_path,
             popup_win,
+            permanent,
         }),
     )
     .await;
 
-    let delete_item = lua.create_async_function(|lua: &Lua, ()| async move {
+    let delete_item = keymap_fn(lua, |lua: &Lua, ()| async move {
         let DeleteItemsCb {
             popup_win,
             file_path,
+            permanent,
         } = NeoBridge::consume("del_popup").await?;
 
-        if file_path.is_file() {
-            let _ = fs::remove_file(&*file_path);
-        } else if file_path.is_dir() {
-            let _ = fs::remove_dir_all(&*file_path);
-        }
+        popup_win.close(lua, false)?;
 
-        let mut instances = CONTAINER.instances.write().await;
-        let instance = instances.get_mut(&AppState::active_buf()).unwrap();
-
-        let selection = CONTAINER.selection.read().await;
-        instance.set_buffer_content(lua, &selection).await?;
+        let paths = vec![file_path];
+        Job::start(lua, JobKind::Delete { paths, permanent }).await?;
 
-        popup_win.close(lua, false)
+        Ok(())
     })?;
 
     popup_buf.set_keymap(lua, Mode::Normal, "q", close_popup)?;
@@ -87,23 +110,192 @@ pub async fn delete_items_popup(lua: &Lua, _: ()) -> LuaResult<()> {
     Ok(())
 }
 
+pub async fn paste_conflict_popup(lua: &Lua, plan: PastePlan) -> LuaResult<()> {
+    let popup_buf = NeoBuffer::create(lua, false, true)?;
+
+    let popup_win = NeoPopup::open_win(
+        lua,
+        &popup_buf,
+        true,
+        WinOptions {
+            relative: PopupRelative::Editor,
+            width: Some(PopupSize::Percentage(1.)),
+            height: Some(PopupSize::Fixed(1)),
+            col: Some(PopupSize::Fixed(0)),
+            row: Some(PopupSize::Percentage(1.)),
+            style: Some(PopupStyle::Minimal),
+            border: PopupBorder::Rounded,
+            anchor: Anchor::NorthWest,
+            title: Some(TextType::Tuples(vec![HLText::new(
+                " Overwrite: (o), skip: (s), keep both: (k), apply to all: (O/S/K), cancel: (q) ",
+                "Question",
+            )])),
+            title_pos: PopupAlign::Right,
+            noautocmd: true,
+            ..Default::default()
+        },
+    )?;
+
+    set_conflict_line(lua, popup_buf, &plan)?;
+    NeoBridge::insert("paste_plan", Box::new(plan)).await;
+
+    let close_popup = lua.create_async_function(move |lua: &Lua, ()| async move {
+        let _: PastePlan = NeoBridge::consume("paste_plan").await?;
+        popup_win.close(lua, true)
+    })?;
+
+    popup_buf.set_keymap(lua, Mode::Normal, "q", close_popup)?;
+
+    let keys = [
+        ("o", Resolution::Overwrite, false),
+        ("s", Resolution::Skip, false),
+        ("k", Resolution::KeepBoth, false),
+        ("O", Resolution::Overwrite, true),
+        ("S", Resolution::Skip, true),
+        ("K", Resolution::KeepBoth, true),
+    ];
+
+    for (key, resolution, apply_to_all) in keys {
+        let resolve = keymap_fn(lua, move |lua: &Lua, ()| async move {
+            let mut plan: PastePlan = NeoBridge::consume("paste_plan").await?;
+            plan.resolve(resolution, apply_to_all);
+
+            if plan.next_conflict().is_some() {
+                set_conflict_line(lua, popup_buf, &plan)?;
+                NeoBridge::insert("paste_plan", Box::new(plan)).await;
+
+                Ok(())
+            } else {
+                popup_win.close(lua, false)?;
+                apply_paste_plan(lua, plan).await
+            }
+        })?;
+
+        popup_buf.set_keymap(lua, Mode::Normal, key, resolve)?;
+    }
+
+    Ok(())
+}
+
+fn set_conflict_line(lua: &Lua, popup_buf: NeoBuffer, plan: &PastePlan) -> LuaResult<()> {
+    if let Some(item) = plan.next_conflict() {
+        let resolved = plan
+            .items
+            .iter()
+            .filter(|item| item.conflict && item.resolution.is_some())
+            .count();
+
+        let reason = if item.duplicate {
+            "is the target of another selected item as well"
+        } else {
+            "already exists"
+        };
+
+        let line = format!(
+            "Conflict ({}/{}): {} {reason}",
+            resolved + 1,
+            plan.conflict_count(),
+            item.target.display()
+        );
+
+        popup_buf.set_lines(lua, 0, -1, false, &[line])?;
+    }
+
+    Ok(())
+}
+
+pub async fn preview_operations_popup(lua: &Lua, kind: JobKind) -> LuaResult<()> {
+    let popup_buf = NeoBuffer::create(lua, false, true)?;
+    let lines = kind.preview()?;
+
+    let texts: Vec<String> = lines.iter().map(|line| line.text.clone()).collect();
+    popup_buf.set_lines(lua, 0, -1, false, &texts)?;
+
+    let theme = CONTAINER.theme.read().await;
+
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(highlight) = line.highlight {
+            popup_buf.add_highlight(lua, theme.popup_ns as i32, highlight, i, 0, -1)?;
+        }
+    }
+
+    drop(theme);
+
+    let popup_win = NeoPopup::open_win(
+        lua,
+        &popup_buf,
+        true,
+        WinOptions {
+            relative: PopupRelative::Editor,
+            width: Some(PopupSize::Percentage(0.6)),
+            height: Some(PopupSize::Fixed(lines.len().min(30) as u32)),
+            row: Some(PopupSize::Percentage(0.1)),
+            col: Some(PopupSize::Percentage(0.2)),
+            style: Some(PopupStyle::Minimal),
+            border: PopupBorder::Rounded,
+            title: Some(TextType::Tuples(vec![HLText::new(
+                " Confirm: (enter), cancel: (q) ",
+                "Question",
+            )])),
+            title_pos: PopupAlign::Right,
+            noautocmd: true,
+            ..Default::default()
+        },
+    )?;
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let sources = kind.preview_sources();
+
+    if !sources.is_empty() {
+        let row = lines.len() - 1;
+        count_preview_totals(lua, popup_buf, row, sources, cancelled.clone())?;
+    }
+
+    NeoBridge::insert("preview_job", Box::new(kind)).await;
+
+    let close_cancelled = cancelled.clone();
+    let close_popup = lua.create_async_function(move |lua: &Lua, ()| {
+        close_cancelled.store(true, Ordering::Relaxed);
+
+        async move {
+            let _: JobKind = NeoBridge::consume("preview_job").await?;
+            popup_win.close(lua, true)
+        }
+    })?;
+
+    let confirm = keymap_fn(lua, move |lua: &Lua, ()| {
+        cancelled.store(true, Ordering::Relaxed);
+
+        async move {
+            let kind: JobKind = NeoBridge::consume("preview_job").await?;
+            popup_win.close(lua, false)?;
+
+            confirm_job(lua, kind).await
+        }
+    })?;
+
+    popup_buf.set_keymap(lua, Mode::Normal, "q", close_popup)?;
+    popup_buf.set_keymap(lua, Mode::Normal, "<Cr>", confirm)
+}
+
 pub async fn rename_items_popup(lua: &Lua, _: ()) -> LuaResult<()> {
     let popup_buf = NeoBuffer::create(lua, false, true)?;
 
     let instances = CONTAINER.instances.read().await;
-    let instance = instances.get(&AppState::active_buf()).unwrap();
+    let instance = AppState::active_instance(&instances)?;
 
     //if instance.selection.is_empty() {
 
     //}
 
-    let filename = instance.get_item(lua)?;
-    let filename_len = filename.len();
-    let source_path = instance.cwd.join(filename);
+    let source_path = instance.get_item(lua)?.path;
+    let filename_len = source_path
+        .file_name()
+        .map_or(0, |name| NeoUtils::escape_name(name).len());
 
     NeoBridge::insert("rename_file_path", Box::new(source_path.clone())).await;
 
-    let file_path = source_path.to_string_lossy().to_string();
+    let file_path = NeoUtils::escape_name(source_path.as_os_str());
     let file_path_len = file_path.len();
 
     popup_buf.set_lines(lua, 0, -1, false, &[file_path])?;
@@ -133,17 +325,27 @@ pub async fn rename_items_popup(lua: &Lua, _: ()) -> LuaResult<()> {
 
     popup_win.set_cursor(lua, WinCursor::from_zero_indexed(0, cursor_col as u32))?;
 
-    let rename_item = lua.create_async_function(|lua, ()| async move {
+    let rename_item = keymap_fn(lua, |lua, ()| async move {
         let mut instances = CONTAINER.instances.write().await;
-        let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+        let instance = AppState::active_instance_mut(&mut instances)?;
 
         let source: PathBuf = NeoBridge::consume("rename_file_path").await?;
         let line = NeoApi::get_current_line(lua)?;
-        let target = instance.cwd.join(line);
-
-        // Disallow rename existing files
-        if source.is_file() && !target.is_file() || source.is_dir() && !target.is_dir() {
-            fs::rename(source, target)?;
+        let target = instance.cwd.join(NeoUtils::unescape_name(&line));
+
+        // Disallow renaming onto anything that exists, a replaced item can't be brought back by
+        // undo. Broken symlinks count as well
+        if target.symlink_metadata().is_err() {
+            fs::rename(&source, &target).map_err(TravellerError::io(&source))?;
+
+            CONTAINER
+                .journal
+                .write()
+                .await
+                .record(JournalStep::new(vec![FsOperation::Rename {
+                    from: source,
+                    to: target,
+                }]));
 
             let selection = CONTAINER.selection.read().await;
 
@@ -187,6 +389,178 @@ pub async fn rename_items_popup(lua: &Lua, _: ()) -> LuaResult<()> {
     Ok(())
 }
 
+pub async fn batch_rename_popup(lua: &Lua, _: ()) -> LuaResult<()> {
+    if CONTAINER.selection.read().await.is_empty() {
+        return NeoApi::notify(lua, &"Select the items to rename first");
+    }
+
+    let input_buf = NeoBuffer::create(lua, false, true)?;
+    input_buf.set_lines(lua, 0, -1, false, &["/"])?;
+
+    let input_win = NeoPopup::open_win(
+        lua,
+        &input_buf,
+        true,
+        WinOptions {
+            relative: PopupRelative::Editor,
+            width: Some(PopupSize::Percentage(0.6)),
+            height: Some(PopupSize::Fixed(1)),
+            row: Some(PopupSize::Percentage(0.1)),
+            col: Some(PopupSize::Percentage(0.2)),
+            style: Some(PopupStyle::Minimal),
+            border: PopupBorder::Rounded,
+            title: Some(TextType::Tuples(vec![HLText::new(
+                " Rename selection: pattern/replacement, confirm: (enter), cancel: (escape) ",
+                "Question",
+            )])),
+            title_pos: PopupAlign::Right,
+            ..Default::default()
+        },
+    )?;
+
+    // Right below the input popup
+    let ui_height = NeoApi::list_uis(lua)?.first().map_or(0, |ui| ui.height);
+    let preview_row = (ui_height as f32 * 0.1).round() as u32 + 3;
+
+    let preview = NeoPopup::open(
+        lua,
+        NeoBuffer::create(lua, false, true)?,
+        false,
+        WinOptions {
+            relative: PopupRelative::Editor,
+            width: Some(PopupSize::Percentage(0.6)),
+            height: Some(PopupSize::Fixed(10)),
+            row: Some(PopupSize::Fixed(preview_row)),
+            col: Some(PopupSize::Percentage(0.2)),
+            style: Some(PopupStyle::Minimal),
+            border: PopupBorder::Rounded,
+            focusable: Some(false),
+            title: Some(TextType::Tuples(vec![HLText::new(" Preview ", "Question")])),
+            noautocmd: true,
+            ..Default::default()
+        },
+    )?;
+
+    let preview_buf = preview.buf;
+    let preview_win = preview.win;
+
+    set_rename_preview(lua, preview_buf, "/").await?;
+    input_win.set_cursor(lua, WinCursor::from_zero_indexed(0, 0))?;
+    NeoApi::set_insert_mode(lua, true)?;
+
+    let update_preview = lua.create_async_function(move |lua: &Lua, ()| async move {
+        let line = input_buf.get_lines(lua, 0, 1, false)?.join("");
+        set_rename_preview(lua, preview_buf, &line).await
+    })?;
+
+    NeoApi::create_autocmd(
+        lua,
+        &[AutoCmdEvent::TextChanged, AutoCmdEvent::TextChangedI],
+        AutoCmdOpts {
+            buffer: Some(input_buf.id()),
+            callback: update_preview,
+            desc: None,
+            group: None,
+            pattern: vec![],
+            once: false,
+        },
+    )?;
+
+    let close_preview = lua.create_function(move |lua: &Lua, ()| {
+        preview_win.close(lua, true)?;
+        NeoApi::set_insert_mode(lua, false)
+    })?;
+
+    NeoApi::create_autocmd(
+        lua,
+        &[AutoCmdEvent::BufLeave],
+        AutoCmdOpts {
+            buffer: Some(input_buf.id()),
+            callback: close_preview,
+            desc: None,
+            group: None,
+            pattern: vec![],
+            once: true,
+        },
+    )?;
+
+    let close_popup = lua.create_function(move |lua: &Lua, ()| input_win.close(lua, true))?;
+
+    let rename_selection = keymap_fn(lua, move |lua: &Lua, ()| async move {
+        let line = input_buf.get_lines(lua, 0, 1, false)?.join("");
+        let (pattern, replacement) = line.split_once('/').unwrap_or((&line, ""));
+
+        let selection = CONTAINER.selection.read().await;
+        let plan = RenamePlan::new(&selection, pattern, replacement);
+        drop(selection);
+
+        let plan = match plan {
+            Ok(plan) => plan,
+            Err(err) => return NeoApi::notify(lua, &format!("Rename refused: {err}")),
+        };
+
+        input_win.close(lua, true)?;
+
+        let (operations, failed) = plan.execute();
+        let dirs: Vec<PathBuf> = operations
+            .iter()
+            .flat_map(FsOperation::affected_dirs)
+            .collect();
+
+        CONTAINER
+            .journal
+            .write()
+            .await
+            .record(JournalStep::new(operations));
+
+        clear_selection(lua).await?;
+        AppState::refresh_instances(lua, &dirs).await?;
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+
+        NeoPopup::notify(
+            lua,
+            PopupNotify {
+                level: PopupLevel::Error,
+                title: "Rename selection".to_string(),
+                messages: failed,
+                duration: Duration::from_secs(5),
+            },
+        )
+    })?;
+
+    input_buf.set_keymap(lua, Mode::Normal, "<Esc>", close_popup)?;
+    input_buf.set_keymap(lua, Mode::Normal, "<Cr>", rename_selection.clone())?;
+    input_buf.set_keymap(lua, Mode::Insert, "<Cr>", rename_selection)
+}
+
+async fn set_rename_preview(lua: &Lua, preview_buf: NeoBuffer, line: &str) -> LuaResult<()> {
+    let (pattern, replacement) = line.split_once('/').unwrap_or((line, ""));
+
+    let selection = CONTAINER.selection.read().await;
+    let plan = RenamePlan::new(&selection, pattern, replacement);
+    drop(selection);
+
+    let (lines, highlight) = match plan {
+        Ok(plan) if plan.renames.is_empty() => (vec!["Nothing to rename".to_string()], "Comment"),
+        Ok(plan) => (plan.preview(), "Normal"),
+        Err(err) => (vec![err], "ErrorMsg"),
+    };
+
+    preview_buf.set_lines(lua, 0, -1, false, &lines)?;
+
+    let theme = CONTAINER.theme.read().await;
+    preview_buf.clear_namespace(lua, theme.popup_ns as i32, 0, -1)?;
+
+    for i in 0..lines.len() {
+        preview_buf.add_highlight(lua, theme.popup_ns as i32, highlight, i, 0, -1)?;
+    }
+
+    Ok(())
+}
+
 pub async fn show_selection_popup(
     lua: &Lua,
     selection: &SelectionData,
@@ -200,7 +574,8 @@ pub async fn show_selection_popup(
         "[pm] paste as move",
         "[pc] paste as copy",
         "[Rs] rename",
-        "[ds] delete",
+        "[ds] trash",
+        "[dS] delete forever",
     ];
 
     if count == 0 {
@@ -243,25 +618,27 @@ pub async fn show_selection_popup(
 
 pub async fn update_selection_popup(lua: &Lua, _: ()) -> LuaResult<()> {
     let mut instances = CONTAINER.instances.write().await;
-    let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+    let instance = AppState::active_instance_mut(&mut instances)?;
 
     let item = instance.get_item(lua)?;
+    // Nested items of the tree view are selected in their own directory
+    let dir = item.dir().to_path_buf();
 
     let mut selection = CONTAINER.selection.write().await;
-    let path_items = selection.get_mut(&instance.cwd);
+    let path_items = selection.get_mut(&dir);
 
     if let Some(path_items) = path_items {
-        if path_items.contains(&item) {
-            path_items.remove(&item);
+        if path_items.contains(&item.name) {
+            path_items.remove(&item.name);
 
             if path_items.is_empty() {
-                selection.remove(&instance.cwd);
+                selection.remove(&dir);
             }
         } else {
-            path_items.insert(item);
+            path_items.insert(item.name);
         }
     } else {
-        selection.insert(instance.cwd.clone(), [item].into());
+        selection.insert(dir, [item.name].into());
     }
 
     show_selection_popup(lua, &selection, instance).await
@@ -320,27 +697,36 @@ pub async fn create_items_popup(lua: &Lua, _: ()) -> LuaResult<()> {
 
     popup_buf.set_keymap(lua, Mode::Insert, "<Esc>", popup_leave_event)?;
 
-    let confirm_selection = lua.create_async_function(move |lua: &Lua, _: ()| async move {
-        let lines = popup_buf.get_lines(lua, 0, 1, false)?;
-
-        let items_cmd = lines[0].to_string();
+    let confirm_selection = keymap_fn(lua, move |lua: &Lua, _: ()| async move {
+        let items_cmd = popup_buf.get_lines(lua, 0, 1, false)?.join("");
 
         let quote_count = items_cmd.chars().filter(|c| *c == '"').count();
 
-        if quote_count % 2 == 0 {
-            let mut instances = CONTAINER.instances.write().await;
-            let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+        if quote_count % 2 != 0 {
+            let msg = "Close the quotes of names with spaces".to_string();
+            return Err(TravellerError::InvalidInput(msg).into());
+        }
 
-            create_items(instance, items_cmd)?;
+        let mut instances = CONTAINER.instances.write().await;
+        let instance = AppState::active_instance_mut(&mut instances)?;
 
-            let selection = CONTAINER.selection.read().await;
-            instance.set_buffer_content(lua, &selection).await?;
+        // What has been created before a failure can still be undone
+        let mut operations = vec![];
+        let created = create_items(instance, items_cmd, &mut operations);
 
-            // TODO feedback
-            popup_win.close(lua, false)?;
-        }
+        CONTAINER
+            .journal
+            .write()
+            .await
+            .record(JournalStep::new(operations));
 
-        Ok(())
+        created?;
+
+        let selection = CONTAINER.selection.read().await;
+        instance.set_buffer_content(lua, &selection).await?;
+
+        // TODO feedback
+        popup_win.close(lua, false)
     })?;
 
     popup_buf.set_keymap(lua, Mode::Insert, "<Cr>", confirm_selection)
@@ -380,36 +766,58 @@ fn split_items(mut items_cmd: String) -> Vec<String> {
     items
 }
 
-fn create_items(instance: &AppInstance, items_cmd: String) -> io::Result<()> {
+fn create_items(
+    instance: &AppInstance,
+    items_cmd: String,
+    operations: &mut Vec<FsOperation>,
+) -> TravellerResult<()> {
     let items = split_items(items_cmd);
 
     for item in items.iter() {
         let path = instance.cwd.join(item);
 
         if item.ends_with('/') {
-            if path.is_dir() {
-                continue;
-            }
-            fs::create_dir_all(path)?;
+            create_dirs(&path, operations)?;
         } else if path.is_file() || path.is_symlink() {
             continue;
         } else {
             if let Some(parent) = path.parent() {
-                if !parent.is_dir() {
-                    fs::create_dir_all(parent)?;
-                }
+                create_dirs(parent, operations)?;
             }
 
-            fs::File::create(path)?;
+            fs::File::create(&path).map_err(TravellerError::io(&path))?;
+
+            operations.push(FsOperation::Create {
+                path,
+                is_dir: false,
+            });
         }
     }
 
     Ok(())
 }
 
+/// Creates the missing directories one at a time, so undo removes every one of them
+fn create_dirs(dir: &Path, operations: &mut Vec<FsOperation>) -> TravellerResult<()> {
+    let missing: Vec<_> = dir.ancestors().take_while(|path| !path.is_dir()).collect();
+
+    for path in missing.into_iter().rev() {
+        fs::create_dir(path).map_err(TravellerError::io(path))?;
+
+        operations.push(FsOperation::Create {
+            path: path.to_path_buf(),
+            is_dir: true,
+        });
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
-    use crate::popup::split_items;
+    use crate::journal::FsOperation;
+    use crate::popup::{create_dirs, split_items};
+    use crate::test_utils::TempDir;
 
     #[test]
     pub fn test() {
@@ -421,4 +829,22 @@ mod test {
         assert_eq!("css/", items[2].as_str());
         assert_eq!(items.len(), 3);
     }
+
+    #[test]
+    pub fn create_parent_dirs() {
+        let root = TempDir::new();
+        let mut operations = vec![];
+        create_dirs(&root.join("a/b"), &mut operations).unwrap();
+
+        let created: Vec<_> = operations
+            .iter()
+            .map(|operation| match operation {
+                FsOperation::Create { path, is_dir: true } => path.clone(),
+                operation => panic!("{operation:?}"),
+            })
+            .collect();
+
+        assert_eq!(vec![root.join("a"), root.join("a/b")], created);
+        assert!(root.join("a/b").is_dir());
+    }
 }
@@ -0,0 +1,325 @@
+use crate::sort::SortMode;
+use crate::state::{nav_items, AppInstance, AppState};
+use crate::utils::NeoUtils;
+use crate::CONTAINER;
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::*;
+use std::{
+    fs,
+    io::Read,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Cursor movements within this time only update the preview once
+const DEBOUNCE_MS: u32 = 80;
+const MAX_LINES: usize = 200;
+const HEX_DUMP_BYTES: usize = 256;
+
+static PREVIEW_TICK: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Preview {
+    Text(Vec<String>),
+    Dir(Vec<String>),
+    /// Header followed by a hex dump
+    Binary(Vec<String>),
+    Message(String),
+}
+
+impl Preview {
+    pub fn read(
+        path: &Path,
+        max_size: u64,
+        show_hidden: bool,
+        sort: SortMode,
+        case_insensitive: bool,
+    ) -> Self {
+        if path.is_dir() {
+            return match nav_items(path, 0, show_hidden, sort, case_insensitive) {
+                Ok(items) if items.is_empty() => Self::Message("Empty directory".to_string()),
+                Ok(items) => Self::Dir(items.into_iter().map(|item| item.name).collect()),
+                Err(err) => Self::Message(err.to_string()),
+            };
+        }
+
+        let size = match fs::metadata(path) {
+            Ok(metadata) if !metadata.is_file() => {
+                return Self::Message("Special file, not previewed".to_string())
+            }
+            Ok(metadata) => metadata.len(),
+            Err(err) => return Self::Message(err.to_string()),
+        };
+
+        if size > max_size {
+            let size = NeoUtils::human_size(size);
+            return Self::Message(format!("Too large to preview, {size}"));
+        }
+
+        let mut bytes = vec![];
+        let read =
+            fs::File::open(path).and_then(|file| file.take(max_size).read_to_end(&mut bytes));
+
+        if let Err(err) = read {
+            return Self::Message(err.to_string());
+        }
+
+        // Same check as git: a NUL byte in the start of the file makes it binary
+        if bytes.iter().take(8000).any(|byte| *byte == 0) {
+            return Self::Binary(hex_dump(&bytes, size));
+        }
+
+        let text = String::from_utf8_lossy(&bytes);
+        Self::Text(text.lines().take(MAX_LINES).map(String::from).collect())
+    }
+
+    fn lines(&self) -> Vec<String> {
+        match self {
+            Self::Text(lines) | Self::Dir(lines) | Self::Binary(lines) => lines.clone(),
+            Self::Message(msg) => vec![msg.clone()],
+        }
+    }
+}
+
+fn hex_dump(bytes: &[u8], size: u64) -> Vec<String> {
+    let mut lines = vec![format!("Binary file, {size} bytes"), String::new()];
+
+    for (i, chunk) in bytes.chunks(16).take(HEX_DUMP_BYTES / 16).enumerate() {
+        let hex: Vec<_> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|byte| {
+                if byte.is_ascii_graphic() || *byte == b' ' {
+                    *byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        lines.push(format!("{:08x}  {:<47}  {ascii}", i * 16, hex.join(" ")));
+    }
+
+    lines
+}
+
+impl AppInstance {
+    fn open_preview(&mut self, lua: &Lua) -> LuaResult<()> {
+        let popup_buf = NeoBuffer::create(lua, false, true)?;
+
+        let popup = NeoPopup::open(
+            lua,
+            popup_buf,
+            false,
+            WinOptions {
+                relative: PopupRelative::Win,
+                win: Some(self.win.id()),
+                width: Some(PopupSize::Percentage(0.5)),
+                height: Some(PopupSize::Percentage(0.8)),
+                col: Some(PopupSize::Percentage(0.5)),
+                row: Some(PopupSize::Fixed(0)),
+                style: Some(PopupStyle::Minimal),
+                border: PopupBorder::Rounded,
+                anchor: Anchor::NorthWest,
+                focusable: Some(false),
+                title: Some(TextType::Tuples(vec![HLText::new(" Preview ", "Question")])),
+                title_pos: PopupAlign::Left,
+                noautocmd: true,
+                ..Default::default()
+            },
+        )?;
+
+        self.preview = Some(popup);
+
+        Ok(())
+    }
+
+    pub fn close_preview(&mut self, lua: &Lua) -> LuaResult<()> {
+        if let Some(popup) = self.preview.take() {
+            popup.win.close(lua, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Shows the item under the cursor, opens the window if it was closed by leaving the buffer
+    pub async fn update_preview(&mut self, lua: &Lua) -> LuaResult<()> {
+        if !self.shows_preview() {
+            return Ok(());
+        }
+
+        if self.preview.is_none() {
+            self.open_preview(lua)?;
+        }
+
+        let Some(popup) = &self.preview else {
+            return Ok(());
+        };
+
+        // The cursor of the navigation window, a popup of the instance may be focused
+        let row = self.win.get_cursor(lua)?.row_zero_indexed() as usize;
+        let item = self.buf_content.get(row);
+
+        let preview = match item {
+            None => Preview::Message("Empty directory".to_string()),
+            Some(item) => {
+                let config = CONTAINER.config.read().await;
+
+                Preview::read(
+                    &item.path,
+                    config.preview_max_size,
+                    self.show_hidden,
+                    self.sort,
+                    config.sort_case_insensitive,
+                )
+            }
+        };
+
+        let syntax = match (&preview, item) {
+            (Preview::Text(_), Some(item)) => preview_filetype(lua, &item.path)?,
+            _ => None,
+        };
+
+        popup.buf.set_lines(lua, 0, -1, false, &preview.lines())?;
+        popup
+            .buf
+            .set_option_value(lua, "syntax", syntax.unwrap_or_default())?;
+
+        let theme = CONTAINER.theme.read().await;
+        let ns = theme.popup_ns as i32;
+        popup.buf.clear_namespace(lua, ns, 0, -1)?;
+
+        match &preview {
+            Preview::Dir(names) => {
+                for (i, name) in names.iter().enumerate() {
+                    if name.ends_with('/') {
+                        popup.buf.add_highlight(lua, ns, "Directory", i, 0, -1)?;
+                    }
+                }
+            }
+            Preview::Binary(_) => {
+                popup.buf.add_highlight(lua, ns, "WarningMsg", 0, 0, -1)?;
+            }
+            Preview::Message(_) => {
+                popup.buf.add_highlight(lua, ns, "Comment", 0, 0, -1)?;
+            }
+            Preview::Text(_) => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Filetype of the item for `syntax`, the `filetype` option would start ftplugins
+fn preview_filetype(lua: &Lua, path: &Path) -> LuaResult<Option<String>> {
+    let filetype: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("filetype")?;
+    let match_filetype: LuaFunction = filetype.get("match")?;
+
+    let args = lua.create_table()?;
+    args.set("filename", path.to_string_lossy().to_string())?;
+
+    match_filetype.call(args)
+}
+
+pub async fn toggle_preview(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    instance.show_preview = !instance.show_preview;
+
+    if instance.shows_preview() {
+        instance.update_preview(lua).await
+    } else {
+        instance.close_preview(lua)
+    }
+}
+
+/// Debounced, only the last of quick cursor movements reads the item
+pub async fn cursor_moved_callback(lua: &Lua, _: AutoCmdCbEvent) -> LuaResult<()> {
+    let tick = PREVIEW_TICK.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let cb = lua.create_async_function(move |lua, ()| async move {
+        if PREVIEW_TICK.load(Ordering::Relaxed) != tick {
+            return Ok(());
+        }
+
+        let mut instances = CONTAINER.instances.write().await;
+
+        match AppState::active_instance_mut(&mut instances) {
+            Ok(instance) => instance.update_preview(lua).await,
+            // The buffer was left in the meantime
+            Err(_) => Ok(()),
+        }
+    })?;
+
+    NeoApi::delay(lua, DEBOUNCE_MS, cb)
+}
+
+/// The floating windows would stay on top of whatever the window shows next
+pub async fn buf_leave_callback(lua: &Lua, ev: AutoCmdCbEvent) -> LuaResult<()> {
+    let Some(buf_id) = ev.buf else {
+        return Ok(());
+    };
+
+    let mut instances = CONTAINER.instances.write().await;
+
+    if let Some(instance) = instances.get_mut(&buf_id) {
+        instance.close_preview(lua)?;
+        instance.close_parent_pane(lua)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Preview;
+    use crate::sort::SortMode;
+    use crate::test_utils::TempDir;
+    use std::fs;
+
+    fn read(root: &TempDir, name: &str) -> Preview {
+        Preview::read(&root.join(name), 64, false, SortMode::default(), false)
+    }
+
+    #[test]
+    pub fn text_preview() {
+        let root = TempDir::new();
+        fs::write(root.join("notes.txt"), "first\nsecond\n").unwrap();
+        fs::write(root.join("large.txt"), "x".repeat(100)).unwrap();
+
+        assert_eq!(
+            Preview::Text(vec!["first".to_string(), "second".to_string()]),
+            read(&root, "notes.txt")
+        );
+        assert_eq!(
+            Preview::Message("Too large to preview, 100 B".to_string()),
+            read(&root, "large.txt")
+        );
+    }
+
+    #[test]
+    pub fn dir_preview() {
+        let root = TempDir::new();
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("notes.txt"), "").unwrap();
+
+        assert_eq!(
+            Preview::Dir(vec!["dir/".to_string(), "notes.txt".to_string()]),
+            read(&root, "")
+        );
+    }
+
+    #[test]
+    pub fn binary_preview() {
+        let root = TempDir::new();
+        fs::write(root.join("image.bin"), [0x89, b'P', b'N', b'G', 0, 1]).unwrap();
+
+        let Preview::Binary(lines) = read(&root, "image.bin") else {
+            panic!("image.bin isn't binary");
+        };
+        assert_eq!("Binary file, 6 bytes", lines[0]);
+        assert!(lines[2].starts_with("00000000  89 50 4e 47 00 01"));
+        assert!(lines[2].ends_with(".PNG.."));
+    }
+}
@@ -0,0 +1,199 @@
+use crate::journal::FsOperation;
+use crate::state::SelectionData;
+use crate::utils::NeoUtils;
+use regex::{Captures, Regex};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Renames of the selection, made from a search pattern and a replacement
+#[derive(Debug, Clone, Default)]
+pub struct RenamePlan {
+    pub renames: Vec<(PathBuf, PathBuf)>,
+}
+
+impl RenamePlan {
+    /// The replacement can use the capture groups of the pattern (`$1`, `${name}`) and a counter
+    /// over the matching items (`{n}`, or `{n:03}` to pad it with zeroes). Items that don't match
+    /// keep their name
+    pub fn new(
+        selection: &SelectionData,
+        pattern: &str,
+        replacement: &str,
+    ) -> Result<Self, String> {
+        let regex = Regex::new(pattern).map_err(|err| err.to_string())?;
+
+        let mut sources: Vec<PathBuf> = selection
+            .iter()
+            .flat_map(|(cwd, names)| names.iter().map(|name| NeoUtils::item_path(cwd, name)))
+            .collect();
+
+        sources.sort();
+
+        let mut renames = vec![];
+        let mut targets = HashSet::new();
+        let mut count = 0;
+
+        for source in sources {
+            let name = file_name(&source);
+
+            if !regex.is_match(&name) {
+                continue;
+            }
+
+            count += 1;
+
+            let new_name = regex.replace(&name, |caps: &Captures| {
+                let mut expanded = String::new();
+                caps.expand(replacement, &mut expanded);
+                expand_counter(&expanded, count)
+            });
+
+            if new_name == name {
+                continue;
+            }
+
+            if matches!(new_name.as_ref(), "" | "." | "..") || new_name.contains('/') {
+                return Err(format!("\"{new_name}\" is not a valid name"));
+            }
+
+            let target = source.with_file_name(NeoUtils::unescape_name(&new_name));
+
+            if !targets.insert(target.clone()) {
+                return Err(format!("{} is the result of two renames", target.display()));
+            }
+
+            if target.symlink_metadata().is_ok() {
+                return Err(format!("{} already exists", target.display()));
+            }
+
+            renames.push((source, target));
+        }
+
+        Ok(Self { renames })
+    }
+
+    /// Before and after of every rename
+    pub fn preview(&self) -> Vec<String> {
+        self.renames
+            .iter()
+            .map(|(source, target)| format!("{} -> {}", file_name(source), file_name(target)))
+            .collect()
+    }
+
+    /// Renames one by one. Returns the applied operations and the failures
+    pub fn execute(self) -> (Vec<FsOperation>, Vec<String>) {
+        let mut operations = vec![];
+        let mut failed = vec![];
+
+        for (from, to) in self.renames {
+            match fs::rename(&from, &to) {
+                Ok(()) => operations.push(FsOperation::Rename { from, to }),
+                Err(err) => failed.push(format!("Failed {}: {err}", from.display())),
+            }
+        }
+
+        (operations, failed)
+    }
+}
+
+/// Escaped, the pattern matches the names the way they're shown
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(NeoUtils::escape_name)
+        .unwrap_or_default()
+}
+
+/// Replaces `{n}` and `{n:0W}` by the counter
+fn expand_counter(replacement: &str, count: usize) -> String {
+    let mut expanded = String::new();
+    let mut rest = replacement;
+
+    while let Some(start) = rest.find("{n") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+
+        let spec = &rest[start + 2..start + len];
+
+        let counter = match spec.strip_prefix(':') {
+            Some(width) => width
+                .parse::<usize>()
+                .ok()
+                .map(|width| format!("{count:0width$}")),
+            None if spec.is_empty() => Some(count.to_string()),
+            None => None,
+        };
+
+        match counter {
+            Some(counter) => {
+                expanded.push_str(&rest[..start]);
+                expanded.push_str(&counter);
+            }
+            None => expanded.push_str(&rest[..start + len + 1]),
+        }
+
+        rest = &rest[start + len + 1..];
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand_counter, RenamePlan};
+    use crate::state::SelectionData;
+    use crate::test_utils::TempDir;
+    use std::{collections::HashMap, fs};
+
+    /// Two photos and a text file, all selected
+    fn selected_files(root: &TempDir) -> SelectionData {
+        let names = ["IMG_12.jpg", "IMG_4.jpg", "notes.txt"];
+
+        for name in names {
+            fs::write(root.join(name), "").unwrap();
+        }
+
+        HashMap::from([(root.to_path_buf(), names.map(String::from).into())])
+    }
+
+    #[test]
+    pub fn rename_counter() {
+        assert_eq!("holiday-007", expand_counter("holiday-{n:03}", 7));
+        assert_eq!("7-{x}", expand_counter("{n}-{x}", 7));
+    }
+
+    #[test]
+    pub fn batch_rename() {
+        let root = TempDir::new();
+        let selection = selected_files(&root);
+
+        let plan = RenamePlan::new(&selection, r"IMG_(\d+)\.jpg", "holiday-{n:03}-$1.jpg").unwrap();
+        assert_eq!(
+            vec![
+                "IMG_12.jpg -> holiday-001-12.jpg",
+                "IMG_4.jpg -> holiday-002-4.jpg"
+            ],
+            plan.preview()
+        );
+
+        let (operations, failed) = plan.execute();
+        assert_eq!((2, 0), (operations.len(), failed.len()));
+        assert!(root.join("holiday-002-4.jpg").is_file());
+    }
+
+    #[test]
+    pub fn rename_collisions() {
+        let root = TempDir::new();
+        let selection = selected_files(&root);
+
+        // Both become IMG_.jpg
+        assert!(RenamePlan::new(&selection, r"\d+", "").is_err());
+
+        fs::write(root.join("notes.md"), "").unwrap();
+        assert!(RenamePlan::new(&selection, r"\.txt$", ".md").is_err());
+    }
+}
@@ -1,20 +1,42 @@
-use crate::popup::{rename_items_popup, show_selection_popup, update_selection_popup};
+use crate::config::Config;
+use crate::details::EntryDetails;
+use crate::dir_size::{self, DirSizes};
+use crate::edit::{self, DirEdit};
+use crate::error::{keymap_fn, TravellerError, TravellerResult};
+use crate::file_kind::FileKind;
+use crate::filter::{self, Filter};
+use crate::fs_ops::{remove_item, Progress};
+use crate::git_status::{self, GitStatus};
+use crate::history::{self, History};
+use crate::job::{cancel_job, Job, JobKind};
+use crate::journal::{FsOperation, Journal};
+use crate::miller;
+use crate::paste::PastePlan;
+use crate::popup::{
+    paste_conflict_popup, preview_operations_popup, rename_items_popup, show_selection_popup,
+    update_selection_popup,
+};
+use crate::preview;
+use crate::sort::{ListedEntry, SortMode};
 use crate::theme::Theme;
+use crate::trash::Trash;
+use crate::tree::{self, TREE_INDENT};
 use crate::utils::NeoUtils;
 use crate::{popup, CONTAINER};
 use neo_api_rs::mlua::prelude::*;
 use neo_api_rs::*;
-use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
-use std::process::Command;
+use std::os::unix::ffi::OsStrExt;
 use std::sync::atomic::{self, AtomicU32};
 use std::{
-    fs::{self, DirEntry},
-    path::PathBuf,
+    fs::{self, Metadata},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
 };
 use tokio::sync::RwLock;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Location {
     pub dir_path: PathBuf,
     pub item: String,
@@ -26,13 +48,193 @@ impl Location {
     }
 }
 
+/// A row of the navigation buffer
+#[derive(Debug, Clone)]
+pub struct NavItem {
+    /// Full path, directories don't keep their trailing slash here
+    pub path: PathBuf,
+    /// The file name as listed, directories end with a slash
+    pub name: String,
+    /// Number of expanded directories the item is nested in
+    pub depth: usize,
+    pub kind: FileKind,
+    /// As read while listing. Symlinks and rows of pending edits have none, their details are
+    /// read when they're shown
+    pub metadata: Option<Metadata>,
+}
+
+impl NavItem {
+    pub fn new(dir: &Path, name: String, depth: usize) -> Self {
+        let kind = if name.ends_with('/') {
+            FileKind::Dir
+        } else {
+            FileKind::File
+        };
+
+        Self {
+            path: NeoUtils::item_path(dir, &name),
+            name,
+            depth,
+            kind,
+            metadata: None,
+        }
+    }
+
+    /// Columns of the long listing, without reading the item again when it was just listed
+    pub fn details(&self) -> Option<EntryDetails> {
+        match &self.metadata {
+            Some(metadata) => Some(EntryDetails::from_metadata(metadata)),
+            None => EntryDetails::read(&self.path),
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// Directory the item is listed in
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(&self.path)
+    }
+
+    /// The line in the buffer, indented in the tree view
+    pub fn line(&self) -> String {
+        format!("{}{}", TREE_INDENT.repeat(self.depth), self.name)
+    }
+
+    /// Relative to the listed directory, so nested items include the directories they're in
+    pub fn relative_name(&self, cwd: &Path) -> String {
+        let relative = self.path.strip_prefix(cwd).unwrap_or(&self.path);
+        let mut name = NeoUtils::escape_name(relative.as_os_str());
+
+        if self.is_dir() {
+            name.push('/');
+        }
+
+        name
+    }
+}
+
+/// What decides the listing besides the directory, taken from the instance and the config
+#[derive(Debug, Clone, Copy)]
+pub struct ListingOptions<'a> {
+    pub show_hidden: bool,
+    pub show_ignored: bool,
+    pub sort: SortMode,
+    pub case_insensitive: bool,
+    pub git_status: bool,
+    /// Expanded directories of the tree view, None when only the directory itself is listed
+    pub expanded: Option<&'a HashSet<PathBuf>>,
+}
+
+/// Everything `reload_buffer` reads from disk, kept apart from the buffer so it can be measured
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub items: Vec<NavItem>,
+    /// Items that git ignores, they're dimmed when shown
+    pub ignored: HashSet<PathBuf>,
+    pub git_status: HashMap<PathBuf, GitStatus>,
+    /// Expanded directories that couldn't be read, they collapse again
+    pub unreadable: Vec<PathBuf>,
+    /// Git only decorates the listing, when it fails the items are listed without it
+    pub git_error: Option<TravellerError>,
+}
+
+impl Listing {
+    pub fn read(cwd: &Path, options: &ListingOptions) -> TravellerResult<Self> {
+        // Looked up once, expanded directories share the repository
+        let git_root = NeoUtils::git_root(cwd);
+
+        let mut listing = Self::default();
+        listing.items = listing.list_dir(cwd, 0, git_root.as_deref(), options)?;
+
+        if let Some(git_root) = git_root.filter(|_| options.git_status) {
+            let names: Vec<_> = listing
+                .items
+                .iter()
+                .map(|item| item.relative_name(cwd))
+                .collect();
+
+            match git_status::git_statuses(cwd, &git_root, &names) {
+                Ok(statuses) => {
+                    listing.git_status = statuses
+                        .into_iter()
+                        .map(|(name, status)| (NeoUtils::item_path(cwd, &name), status))
+                        .collect();
+                }
+                Err(err) => listing.git_error = Some(err),
+            }
+        }
+
+        Ok(listing)
+    }
+
+    /// Expanded directories of the tree view include their content
+    fn list_dir(
+        &mut self,
+        dir: &Path,
+        depth: usize,
+        git_root: Option<&Path>,
+        options: &ListingOptions,
+    ) -> TravellerResult<Vec<NavItem>> {
+        let mut items = nav_items(
+            dir,
+            depth,
+            options.show_hidden,
+            options.sort,
+            options.case_insensitive,
+        )?;
+
+        let names: Vec<_> = items.iter().map(|item| item.name.clone()).collect();
+        let ignored = NeoUtils::git_ignored(dir, git_root, &names).unwrap_or_else(|err| {
+            self.git_error.get_or_insert(err);
+            HashSet::new()
+        });
+
+        if !options.show_ignored {
+            items.retain(|item| !ignored.contains(&item.name));
+        }
+
+        for item in items.iter() {
+            if ignored.contains(&item.name) {
+                self.ignored.insert(item.path.clone());
+            }
+        }
+
+        let Some(expanded) = options.expanded else {
+            return Ok(items);
+        };
+
+        let mut listing = vec![];
+
+        for item in items {
+            let expand = item.is_dir() && expanded.contains(&item.path);
+            let path = item.path.clone();
+
+            listing.push(item);
+
+            if expand {
+                match self.list_dir(&path, depth + 1, git_root, options) {
+                    Ok(content) => listing.extend(content),
+                    Err(_) => self.unreadable.push(path),
+                }
+            }
+        }
+
+        Ok(listing)
+    }
+}
+
 #[derive(Debug)]
 pub struct AppState {
-    pub history_dir: RwLock<PathBuf>,
+    pub history: RwLock<History>,
     pub theme: RwLock<Theme>,
     pub active_buf: AtomicU32,
     pub instances: RwLock<HashMap<u32, AppInstance>>,
     pub selection: RwLock<HashMap<PathBuf, HashSet<String>>>,
+    pub journal: RwLock<Journal>,
+    pub job: RwLock<Option<Job>>,
+    pub config: RwLock<Config>,
 }
 
 pub type SelectionData = HashMap<PathBuf, HashSet<String>>;
@@ -42,12 +244,42 @@ pub struct AppInstance {
     pub win: NeoWindow,
     pub buf: NeoBuffer,
     pub show_hidden: bool,
+    pub show_ignored: bool,
+    /// Items of the listing that git ignores, they're dimmed when shown
+    pub ignored: HashSet<PathBuf>,
+    pub git_status: HashMap<PathBuf, GitStatus>,
+    /// Last git failure that was reported, it isn't reported again on every reload
+    pub git_error: Option<String>,
+    /// Directories are expanded in place instead of being navigated into
+    pub tree: bool,
+    /// Expanded directories of the tree view, kept while navigating
+    pub expanded: HashSet<PathBuf>,
+    /// Narrows the listing while it's typed, cleared when leaving the directory
+    pub filter: Option<Filter>,
+    /// Long listing with size, permissions, owner and modification time
+    pub show_details: bool,
+    pub sort: SortMode,
+    /// Total sizes of the listed directories, computed in the background
+    pub show_dir_sizes: bool,
+    pub dir_sizes: Option<DirSizes>,
+    /// Details per row of `buf_content`, only read while `show_details` is set
+    pub details: Vec<Option<EntryDetails>>,
     pub history: Vec<Location>,
-    pub buf_content: Vec<String>,
+    pub buf_content: Vec<NavItem>,
+    /// Rows of `buf_content` with highlights, the ones around the visible rows
+    pub themed_rows: Range<usize>,
     pub cwd: PathBuf,
     /// This is where traveller needs to return when quiting manually
     pub started_from: PathBuf,
     pub selection_popup: Option<NeoPopup>,
+    /// Floating window next to the navigation showing the item under the cursor
+    pub show_preview: bool,
+    pub preview: Option<NeoPopup>,
+    /// Parent directory on the left and the preview on the right of the listing
+    pub miller: bool,
+    pub parent_pane: Option<NeoPopup>,
+    /// Edited listings per directory while the buffer is in edit mode
+    pub edits: Option<HashMap<PathBuf, DirEdit>>,
 }
 
 unsafe impl Send for AppState {}
@@ -55,17 +287,52 @@ unsafe impl Sync for AppState {}
 
 impl AppState {
     pub fn init(lua: &Lua) -> LuaResult<()> {
-        //self.history_dir = NeoApi::stdpath(lua, StdpathType::State)?;
+        *CONTAINER.history.blocking_write() = History::new(history::history_dir(lua)?);
+        history::flush_on_leave(lua)?;
 
         let mut theme = CONTAINER.theme.blocking_write();
 
         theme.init(lua)
     }
 
+    /// Reloads every instance that shows one of the directories
+    pub async fn refresh_instances(lua: &Lua, dirs: &[PathBuf]) -> LuaResult<()> {
+        let mut instances = CONTAINER.instances.write().await;
+        let selection = CONTAINER.selection.read().await;
+        let active_buf = Self::active_buf();
+
+        for (buf_id, instance) in instances.iter_mut() {
+            if *buf_id == active_buf {
+                instance.set_buffer_content(lua, &selection).await?;
+            } else if dirs.iter().any(|dir| instance.shows_dir(dir)) {
+                instance.reload_buffer(lua, &selection).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn active_buf() -> u32 {
         CONTAINER.active_buf.load(atomic::Ordering::Relaxed)
     }
 
+    /// Instance of the active traveller buffer
+    pub fn active_instance(instances: &HashMap<u32, AppInstance>) -> TravellerResult<&AppInstance> {
+        let buf_id = Self::active_buf();
+        instances
+            .get(&buf_id)
+            .ok_or(TravellerError::NoInstance(buf_id))
+    }
+
+    pub fn active_instance_mut(
+        instances: &mut HashMap<u32, AppInstance>,
+    ) -> TravellerResult<&mut AppInstance> {
+        let buf_id = Self::active_buf();
+        instances
+            .get_mut(&buf_id)
+            .ok_or(TravellerError::NoInstance(buf_id))
+    }
+
     pub fn set_active_buf(idx: u32) -> LuaResult<()> {
         CONTAINER.active_buf.store(idx, atomic::Ordering::Relaxed);
         Ok(())
@@ -81,34 +348,55 @@ impl AppState {
         let filename: Option<String>;
         let cwd: PathBuf;
 
-        if started_from.is_file() {
-            filename = Some(
-                started_from
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string(),
-            );
+        match (started_from.file_name(), started_from.parent()) {
+            (Some(name), Some(parent)) if started_from.is_file() => {
+                filename = Some(NeoUtils::escape_name(name));
+                cwd = parent.to_path_buf();
+            }
+            _ => {
+                filename = None;
+                cwd = started_from.clone();
+            }
+        }
 
-            cwd = started_from.parent().unwrap().to_path_buf();
-        } else {
-            filename = None;
-            cwd = started_from.clone();
-        };
+        let config = CONTAINER.config.read().await;
+        let (hide_ignored, miller) = (config.hide_ignored, config.miller_columns);
+        let show_dir_sizes = config.dir_sizes;
+        drop(config);
+
+        let history = CONTAINER.history.read().await.load();
 
         let mut instance = AppInstance {
             buf,
             win,
             show_hidden: false,
-            history: vec![],
+            show_ignored: !hide_ignored,
+            ignored: HashSet::new(),
+            git_status: HashMap::new(),
+            git_error: None,
+            tree: false,
+            expanded: HashSet::new(),
+            filter: None,
+            show_details: false,
+            sort: SortMode::default(),
+            show_dir_sizes,
+            dir_sizes: None,
+            details: vec![],
+            history,
             buf_content: vec![],
+            themed_rows: 0..0,
             cwd,
             started_from,
             selection_popup: None,
+            show_preview: false,
+            preview: None,
+            miller,
+            parent_pane: None,
+            edits: None,
         };
 
         if let Some(filename) = filename {
-            instance.update_history(filename);
+            instance.update_history(filename).await;
         }
 
         let selection = CONTAINER.selection.read().await;
@@ -125,7 +413,10 @@ impl AppState {
         Self::set_active_buf(buf_id)?;
 
         // Display in bar below
-        NeoApi::set_cmd_file(lua, format!("Traveller ({buf_id})"))?;
+        NeoApi::set_cmd_file(
+            lua,
+            format!("Traveller ({buf_id}) [{}]", SortMode::default()),
+        )?;
 
         // Auto commands
         let buf_enter_aucmd = AutoCmdOpts {
@@ -150,22 +441,73 @@ impl AppState {
 
         NeoApi::create_autocmd(lua, &[AutoCmdEvent::BufWipeout], buf_wipeout_aucmd)?;
 
+        let cursor_moved_aucmd = AutoCmdOpts {
+            buffer: Some(buf_id),
+            callback: lua.create_async_function(preview::cursor_moved_callback)?,
+            pattern: vec![],
+            group: None,
+            desc: None,
+            once: false,
+        };
+
+        NeoApi::create_autocmd(lua, &[AutoCmdEvent::CursorMoved], cursor_moved_aucmd)?;
+
+        let win_scrolled_aucmd = AutoCmdOpts {
+            buffer: Some(buf_id),
+            callback: lua.create_async_function(win_scrolled_callback)?,
+            pattern: vec![],
+            group: None,
+            desc: None,
+            once: false,
+        };
+
+        NeoApi::create_autocmd(lua, &[AutoCmdEvent::WinScrolled], win_scrolled_aucmd)?;
+
+        let buf_leave_aucmd = AutoCmdOpts {
+            buffer: Some(buf_id),
+            callback: lua.create_async_function(preview::buf_leave_callback)?,
+            pattern: vec![],
+            group: None,
+            desc: None,
+            once: false,
+        };
+
+        NeoApi::create_autocmd(lua, &[AutoCmdEvent::BufLeave], buf_leave_aucmd)?;
+
+        let buf_write_aucmd = AutoCmdOpts {
+            buffer: Some(buf_id),
+            callback: lua.create_async_function(edit::buf_write_callback)?,
+            pattern: vec![],
+            group: None,
+            desc: None,
+            once: false,
+        };
+
+        NeoApi::create_autocmd(lua, &[AutoCmdEvent::BufWriteCmd], buf_write_aucmd)?;
+
         Ok(())
     }
 }
 
+/// Every keymap of the navigation buffer, they get out of the way while editing it as text
+pub const NAV_KEYMAPS: [&str; 34] = [
+    "q", "h", "<Left>", "l", "<Cr>", "<Right>", "t", "s", "v", "T", "P", "M", "f", ".", "I", "D",
+    "gs", "S", "O", "c", "e", "dd", "dD", "y", "u", "pc", "pm", "ds", "dS", "r", "Rs", "U",
+    "<C-r>", "<C-c>",
+];
+
 impl AppInstance {
-    fn add_keymaps(&self, lua: &Lua) -> LuaResult<()> {
+    pub fn add_keymaps(&self, lua: &Lua) -> LuaResult<()> {
         let km_opts = self.buf.keymap_opts(true);
 
-        let close_nav = lua.create_async_function(close_navigation)?;
+        let close_nav = keymap_fn(lua, close_navigation)?;
         NeoApi::set_keymap(lua, Mode::Normal, "q", close_nav, km_opts)?;
 
-        let nav_to_parent = lua.create_async_function(navigate_to_parent)?;
+        let nav_to_parent = keymap_fn(lua, navigate_to_parent)?;
         NeoApi::set_keymap(lua, Mode::Normal, "h", nav_to_parent.clone(), km_opts)?;
         NeoApi::set_keymap(lua, Mode::Normal, "<Left>", nav_to_parent, km_opts)?;
 
-        let open_item_in_buffer = lua.create_async_function(open_item_in_buffer)?;
+        let open_item_in_buffer = keymap_fn(lua, open_item_in_buffer)?;
         NeoApi::set_keymap(lua, Mode::Normal, "l", open_item_in_buffer.clone(), km_opts)?;
         NeoApi::set_keymap(
             lua,
@@ -176,42 +518,90 @@ impl AppInstance {
         )?;
         NeoApi::set_keymap(lua, Mode::Normal, "<Right>", open_item_in_buffer, km_opts)?;
 
-        let open_in_tab = lua.create_async_function(open_item_in_tab)?;
+        let open_in_tab = keymap_fn(lua, open_item_in_tab)?;
         NeoApi::set_keymap(lua, Mode::Normal, "t", open_in_tab, km_opts)?;
 
-        let open_in_hsplit = lua.create_async_function(open_item_in_hsplit)?;
+        let open_in_hsplit = keymap_fn(lua, open_item_in_hsplit)?;
         NeoApi::set_keymap(lua, Mode::Normal, "s", open_in_hsplit, km_opts)?;
 
-        let open_in_vsplit = lua.create_async_function(open_item_in_vsplit)?;
+        let open_in_vsplit = keymap_fn(lua, open_item_in_vsplit)?;
         NeoApi::set_keymap(lua, Mode::Normal, "v", open_in_vsplit, km_opts)?;
 
-        let toggle_hidden = lua.create_async_function(toggle_hidden)?;
+        let toggle_tree = keymap_fn(lua, tree::toggle_tree)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "T", toggle_tree, km_opts)?;
+
+        let toggle_preview = keymap_fn(lua, preview::toggle_preview)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "P", toggle_preview, km_opts)?;
+
+        let toggle_miller = keymap_fn(lua, miller::toggle_miller)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "M", toggle_miller, km_opts)?;
+
+        let filter_popup = keymap_fn(lua, filter::filter_popup)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "f", filter_popup, km_opts)?;
+
+        let toggle_hidden = keymap_fn(lua, toggle_hidden)?;
         NeoApi::set_keymap(lua, Mode::Normal, ".", toggle_hidden, km_opts)?;
 
-        let create_items = lua.create_async_function(popup::create_items_popup)?;
+        let toggle_ignored = keymap_fn(lua, toggle_ignored)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "I", toggle_ignored, km_opts)?;
+
+        let toggle_details = keymap_fn(lua, toggle_details)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "D", toggle_details, km_opts)?;
+
+        let toggle_dir_sizes = keymap_fn(lua, dir_size::toggle_dir_sizes)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "gs", toggle_dir_sizes, km_opts)?;
+
+        let cycle_sort = keymap_fn(lua, cycle_sort_key)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "S", cycle_sort, km_opts)?;
+
+        let reverse_sort = keymap_fn(lua, reverse_sort_order)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "O", reverse_sort, km_opts)?;
+
+        let create_items = keymap_fn(lua, popup::create_items_popup)?;
         NeoApi::set_keymap(lua, Mode::Normal, "c", create_items, km_opts)?;
 
-        let delete_items = lua.create_async_function(popup::delete_items_popup)?;
-        NeoApi::set_keymap(lua, Mode::Normal, "dd", delete_items, km_opts)?;
+        let edit_mode = keymap_fn(lua, edit::edit_mode)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "e", edit_mode, km_opts)?;
+
+        let trash_items = keymap_fn(lua, popup::trash_items_popup)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "dd", trash_items, km_opts)?;
+
+        let delete_items = keymap_fn(lua, popup::delete_items_popup)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "dD", delete_items, km_opts)?;
 
-        let select_item = lua.create_async_function(update_selection_popup)?;
+        let select_item = keymap_fn(lua, update_selection_popup)?;
         NeoApi::set_keymap(lua, Mode::Normal, "y", select_item, km_opts)?;
 
-        let undo_selection = lua.create_async_function(undo_selection)?;
+        let undo_selection = keymap_fn(lua, undo_selection)?;
         NeoApi::set_keymap(lua, Mode::Normal, "u", undo_selection, km_opts)?;
 
-        let copy_selection = lua.create_async_function(copy_selection)?;
+        let copy_selection = keymap_fn(lua, copy_selection)?;
         NeoApi::set_keymap(lua, Mode::Normal, "pc", copy_selection, km_opts)?;
 
-        let move_selection = lua.create_async_function(move_selection)?;
+        let move_selection = keymap_fn(lua, move_selection)?;
         NeoApi::set_keymap(lua, Mode::Normal, "pm", move_selection, km_opts)?;
 
-        let delete_selection = lua.create_async_function(delete_selection)?;
-        NeoApi::set_keymap(lua, Mode::Normal, "ds", delete_selection, km_opts)?;
+        let trash_selection = keymap_fn(lua, trash_selection)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "ds", trash_selection, km_opts)?;
 
-        let rename = lua.create_async_function(rename_items_popup)?;
+        let delete_selection = keymap_fn(lua, delete_selection)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "dS", delete_selection, km_opts)?;
+
+        let rename = keymap_fn(lua, rename_items_popup)?;
         NeoApi::set_keymap(lua, Mode::Normal, "r", rename, km_opts)?;
 
+        let rename_selection = keymap_fn(lua, popup::batch_rename_popup)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "Rs", rename_selection, km_opts)?;
+
+        let undo_operation = keymap_fn(lua, undo_operation)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "U", undo_operation, km_opts)?;
+
+        let redo_operation = keymap_fn(lua, redo_operation)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "<C-r>", redo_operation, km_opts)?;
+
+        let cancel_job = keymap_fn(lua, cancel_job)?;
+        NeoApi::set_keymap(lua, Mode::Normal, "<C-c>", cancel_job, km_opts)?;
+
         Ok(())
     }
 
@@ -233,9 +623,18 @@ impl AppInstance {
         Ok(())
     }
 
-    pub fn get_item(&self, lua: &Lua) -> LuaResult<String> {
+    /// None in an empty listing, or one a filter emptied
+    pub fn item_under_cursor(&self, lua: &Lua) -> LuaResult<Option<NavItem>> {
         let cursor = NeoWindow::CURRENT.get_cursor(lua)?;
-        Ok(self.buf_content[cursor.row_zero_indexed() as usize].clone())
+        Ok(self
+            .buf_content
+            .get(cursor.row_zero_indexed() as usize)
+            .cloned())
+    }
+
+    pub fn get_item(&self, lua: &Lua) -> LuaResult<NavItem> {
+        self.item_under_cursor(lua)?
+            .ok_or_else(|| TravellerError::InvalidInput("No item under the cursor".into()).into())
     }
 
     pub async fn set_buffer_content<'a>(
@@ -245,21 +644,109 @@ impl AppInstance {
     ) -> LuaResult<()> {
         NeoApi::set_cwd(lua, &self.cwd)?;
 
+        self.reload_buffer(lua, selection).await?;
+        self.set_nav_cursor(lua)?;
+
+        // The child column shifts along when navigating
+        self.update_preview(lua).await
+    }
+
+    /// Rereads the directory without touching the cursor or the working directory, so it can be
+    /// used for instances that aren't active
+    pub async fn reload_buffer(&mut self, lua: &Lua, selection: &SelectionData) -> LuaResult<()> {
         self.buf.set_option_value(lua, "modifiable", true)?;
-        self.buf_content = nav_buffer_lines(&self.cwd, self.show_hidden)?;
-        self.buf.set_lines(lua, 0, -1, true, &self.buf_content)?;
-        self.buf.set_option_value(lua, "modifiable", false)?;
+        let config = CONTAINER.config.read().await;
+
+        let cwd = self.cwd.clone();
+        let options = ListingOptions {
+            show_hidden: self.show_hidden,
+            show_ignored: self.show_ignored,
+            sort: self.sort,
+            case_insensitive: config.sort_case_insensitive,
+            git_status: config.git_status,
+            // Edits are made to the listing of a single directory
+            expanded: (self.tree && !self.is_editing()).then_some(&self.expanded),
+        };
+
+        let listing = Listing::read(&cwd, &options)?;
+        drop(config);
+
+        for dir in listing.unreadable.iter() {
+            self.expanded.remove(dir);
+        }
+
+        self.buf_content = listing.items;
+        self.ignored = listing.ignored;
+        self.git_status = listing.git_status;
+
+        let git_error = listing.git_error.map(|err| err.to_string());
+
+        if let Some(err) = git_error
+            .as_ref()
+            .filter(|err| self.git_error.as_ref() != Some(err))
+        {
+            NeoApi::notify(lua, err)?;
+        }
+
+        self.git_error = git_error;
+
+        if self.filter.as_ref().is_some_and(|filter| filter.dir != cwd) {
+            self.filter = None;
+        }
+
+        // Edits are diffed against the whole listing
+        match &self.filter {
+            Some(filter) if !self.is_editing() => {
+                self.buf_content = filter.apply(std::mem::take(&mut self.buf_content));
+            }
+            _ => {}
+        }
+
+        let edit = self.edits.as_ref().and_then(|edits| edits.get(&self.cwd));
+
+        if let Some(edit) = edit {
+            // Pending edits are diffed against the listing they started from
+            self.buf_content = edit
+                .original
+                .iter()
+                .map(|name| NavItem::new(&cwd, name.clone(), 0))
+                .collect();
+            self.buf.set_lines(lua, 0, -1, true, &edit.edited)?;
+        } else {
+            let lines: Vec<_> = self.buf_content.iter().map(NavItem::line).collect();
+            self.buf.set_lines(lua, 0, -1, true, &lines)?;
+        }
+
+        self.details = if self.show_details {
+            self.buf_content.iter().map(NavItem::details).collect()
+        } else {
+            vec![]
+        };
+
+        self.buf
+            .set_option_value(lua, "modifiable", self.is_editing())?;
+
+        if self.is_editing() {
+            self.buf.set_option_value(lua, "modified", edit.is_some())?;
+        }
+
+        if self.show_dir_sizes {
+            self.start_dir_sizes(lua)?;
+        }
 
         self.theme_nav_buffer(lua, selection).await?;
-        self.set_nav_cursor(lua)?;
+        self.update_parent_pane(lua).await
+    }
 
-        Ok(())
+    /// The directory is listed, either as the current one or expanded in the tree view
+    pub fn shows_dir(&self, dir: &Path) -> bool {
+        self.cwd == dir || self.tree && self.expanded.contains(dir)
     }
 
     fn set_nav_cursor(&mut self, lua: &Lua) -> LuaResult<()> {
         if let Some(location) = self.history.iter().find(|loc| loc.dir_path == self.cwd) {
             for (row, item) in self.buf_content.iter().enumerate() {
-                if &location.item == item {
+                if location.item == item.relative_name(&self.cwd) {
                     let cursor = WinCursor::from_zero_indexed(row as u32, 0);
                     return self.win.set_cursor(lua, cursor);
                 }
@@ -278,42 +765,97 @@ impl AppInstance {
         self.history.iter_mut().find(|his| his.dir_path == self.cwd)
     }
 
-    fn update_history(&mut self, item: String) {
-        if let Some(location) = self.get_location() {
-            location.item = item;
+    pub async fn update_history(&mut self, item: String) {
+        let location = Location::new(self.cwd.clone(), item.clone());
+
+        // Kept in memory, it's written once the buffer is wiped out or Neovim quits
+        CONTAINER.history.write().await.record(location.clone());
+
+        if let Some(existing) = self.get_location() {
+            existing.item = item;
             return;
         }
 
-        let dir_path = self.cwd.clone();
-        self.history.push(Location::new(dir_path, item));
+        self.history.push(location);
     }
 }
 
 async fn buf_enter_callback<'a>(lua: &Lua, ev: AutoCmdCbEvent) -> LuaResult<()> {
-    AppState::set_active_buf(ev.buf.unwrap())?;
+    let Some(buf_id) = ev.buf else {
+        return Ok(());
+    };
+
+    AppState::set_active_buf(buf_id)?;
 
     let cb = lua.create_async_function(|lua, ()| async {
         let mut instances = CONTAINER.instances.write().await;
-        let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+        let instance = AppState::active_instance_mut(&mut instances)?;
 
         let selection = CONTAINER.selection.read().await;
 
-        show_selection_popup(lua, &selection, instance).await
+        show_selection_popup(lua, &selection, instance).await?;
+        instance.open_parent_pane(lua).await?;
+        instance.update_preview(lua).await
     })?;
 
     NeoApi::delay(lua, 32, cb)
 }
 
+/// Only the rows around the visible ones are themed
+async fn win_scrolled_callback(lua: &Lua, ev: AutoCmdCbEvent) -> LuaResult<()> {
+    let Some(buf_id) = ev.buf else {
+        return Ok(());
+    };
+
+    let mut instances = CONTAINER.instances.write().await;
+
+    let Some(instance) = instances.get_mut(&buf_id) else {
+        return Ok(());
+    };
+
+    if instance.scrolled(lua)? {
+        let selection = CONTAINER.selection.read().await;
+        instance.theme_nav_buffer(lua, &selection).await?;
+    }
+
+    Ok(())
+}
+
 async fn buf_wipeout_callback(lua: &Lua, ev: AutoCmdCbEvent) -> LuaResult<()> {
-    let buf_id = ev.buf.unwrap();
+    let Some(buf_id) = ev.buf else {
+        return Ok(());
+    };
+
+    // The buffer is gone once the deferred callback runs, `:q` and `:bd` leave the cursor here
+    let row = last_cursor_row(lua, buf_id)?;
 
     let defer_cb = lua.create_async_function(move |lua, ()| async move {
         let mut instances = CONTAINER.instances.write().await;
-        let instance = instances.get_mut(&buf_id).unwrap();
-        let selection = CONTAINER.selection.read().await;
-        instance.close_selection_popup(lua, &selection).await?;
 
-        let _ = instances.remove(&buf_id);
+        if let Some(mut instance) = instances.remove(&buf_id) {
+            // Edited rows don't match the listing
+            let item = row
+                .checked_sub(1)
+                .and_then(|row| instance.buf_content.get(row))
+                .filter(|_| instance.edits.is_none())
+                .map(|item| item.relative_name(&instance.cwd));
+
+            if let Some(item) = item {
+                instance.update_history(item).await;
+            }
+
+            let selection = CONTAINER.selection.read().await;
+            instance.close_selection_popup(lua, &selection).await?;
+            instance.close_preview(lua)?;
+            instance.close_parent_pane(lua)?;
+            instance.cancel_dir_sizes();
+        }
+
+        drop(instances);
+
+        if let Err(err) = CONTAINER.history.write().await.flush() {
+            NeoApi::notify(lua, &err)?;
+        }
 
         Ok(())
     })?;
@@ -321,53 +863,82 @@ async fn buf_wipeout_callback(lua: &Lua, ev: AutoCmdCbEvent) -> LuaResult<()> {
     NeoApi::delay(lua, 64, defer_cb)
 }
 
-fn copy_items_or_dir(lua: &Lua, source: PathBuf, target: PathBuf) -> LuaResult<()> {
-    if source.is_dir() {
-        let result = Command::new("cp")
-            .args(["-r", &source.to_string_lossy(), &target.to_string_lossy()])
-            .output()
-            .map_err(LuaError::external)?;
+/// One based row of the `"` mark, 0 if the buffer was never left
+fn last_cursor_row(lua: &Lua, buf_id: u32) -> LuaResult<usize> {
+    let api: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("api")?;
+    let get_mark: LuaFunction = api.get("nvim_buf_get_mark")?;
+    let mark: Vec<usize> = get_mark.call((buf_id, "\""))?;
 
-        if !result.status.success() {
-            NeoApi::notify(lua, &String::from_utf8_lossy(&result.stderr))?;
-        }
-    } else {
-        fs::copy(source, target)?;
-    }
-
-    Ok(())
+    Ok(mark.first().copied().unwrap_or(0))
 }
 
 async fn copy_or_move_selection(lua: &Lua, copy: bool) -> LuaResult<()> {
-    let mut instances = CONTAINER.instances.write().await;
-    let instance = instances.get_mut(&AppState::active_buf()).unwrap();
-
-    let mut selection = CONTAINER.selection.write().await;
+    let instances = CONTAINER.instances.read().await;
+    let instance = AppState::active_instance(&instances)?;
 
-    for paths in selection.iter() {
-        let cwd = paths.0;
+    let selection = CONTAINER.selection.read().await;
+    let mut plan = PastePlan::new(&selection, &instance.cwd, copy);
+    plan.options = CONTAINER.config.read().await.copy_options();
 
-        for item in paths.1.iter() {
-            let source = cwd.join(item);
+    drop(selection);
+    drop(instances);
 
-            if copy {
-                let mut target = instance.cwd.join(item);
+    run_selection_job(lua, JobKind::Paste(plan)).await
+}
 
-                if source == target {
-                    target = instance.cwd.join(format!("copy_{}", item));
-                }
+/// Asks for confirmation first when the `preview_operations` option is enabled
+async fn run_selection_job(lua: &Lua, kind: JobKind) -> LuaResult<()> {
+    if CONTAINER.config.read().await.preview_operations {
+        preview_operations_popup(lua, kind).await
+    } else {
+        confirm_job(lua, kind).await
+    }
+}
 
-                copy_items_or_dir(lua, source, target)?;
-            } else {
-                fs::rename(source, instance.cwd.join(item))?;
+/// Continues a confirmed job, paste conflicts get resolved before it starts
+pub async fn confirm_job(lua: &Lua, kind: JobKind) -> LuaResult<()> {
+    match kind {
+        JobKind::Edit(plan) => {
+            // A refused job keeps the edits, they can be written again
+            if Job::start(lua, JobKind::Edit(plan)).await? {
+                edit::leave_edit_modes(lua).await?;
             }
+
+            Ok(())
+        }
+        JobKind::Paste(plan) if plan.next_conflict().is_some() => {
+            paste_conflict_popup(lua, plan).await
         }
+        JobKind::Paste(plan) => apply_paste_plan(lua, plan).await,
+        kind => start_selection_job(lua, kind).await,
+    }
+}
+
+/// Pastes the selection once every conflict has been resolved
+pub async fn apply_paste_plan(lua: &Lua, plan: PastePlan) -> LuaResult<()> {
+    start_selection_job(lua, JobKind::Paste(plan)).await
+}
+
+/// The selection is kept when the job is refused because another one is running
+async fn start_selection_job(lua: &Lua, kind: JobKind) -> LuaResult<()> {
+    if Job::start(lua, kind).await? {
+        clear_selection(lua).await?;
     }
 
+    Ok(())
+}
+
+/// The selection is consumed by the paste or delete that uses it
+pub async fn clear_selection(lua: &Lua) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let mut selection = CONTAINER.selection.write().await;
     *selection = HashMap::new();
 
-    instance.close_selection_popup(lua, &selection).await?;
-    instance.set_buffer_content(lua, &selection).await
+    if let Some(instance) = instances.get_mut(&AppState::active_buf()) {
+        instance.close_selection_popup(lua, &selection).await?;
+    }
+
+    Ok(())
 }
 
 async fn move_selection(lua: &Lua, _: ()) -> LuaResult<()> {
@@ -386,29 +957,72 @@ async fn copy_selection(lua: &Lua, _: ()) -> LuaResult<()> {
     Ok(())
 }
 
+async fn delete_selection_items(lua: &Lua, permanent: bool) -> LuaResult<()> {
+    let selection = CONTAINER.selection.read().await;
+    let mut paths = vec![];
+
+    for (cwd, items) in selection.iter() {
+        paths.extend(items.iter().map(|item| NeoUtils::item_path(cwd, item)));
+    }
+
+    drop(selection);
+
+    run_selection_job(lua, JobKind::Delete { paths, permanent }).await
+}
+
+async fn trash_selection(lua: &Lua, _: ()) -> LuaResult<()> {
+    if let Err(err) = delete_selection_items(lua, false).await {
+        NeoApi::notify(lua, &err)?;
+    }
+
+    Ok(())
+}
+
 async fn delete_selection(lua: &Lua, _: ()) -> LuaResult<()> {
-    let mut instances = CONTAINER.instances.write().await;
-    let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+    if let Err(err) = delete_selection_items(lua, true).await {
+        NeoApi::notify(lua, &err)?;
+    }
 
-    let mut selection = CONTAINER.selection.write().await;
+    Ok(())
+}
 
-    for paths in selection.iter() {
-        let cwd = paths.0;
+/// Moves the item to the trash, or removes it for good when `permanent` is set. Only the first
+/// can be undone so only that returns an operation for the journal
+pub fn delete_item(
+    path: &Path,
+    permanent: bool,
+    progress: &Progress,
+) -> io::Result<Option<FsOperation>> {
+    if !permanent {
+        return Ok(Some(FsOperation::Trash(Trash::put(path)?)));
+    }
 
-        for item in paths.1.iter() {
-            let target = cwd.join(item);
+    remove_item(path, progress)?;
 
-            if target.is_dir() {
-                fs::remove_dir_all(target)?;
-            } else if target.is_file() {
-                fs::remove_file(target)?;
-            }
-        }
+    Ok(None)
+}
+
+async fn undo_operation(lua: &Lua, _: ()) -> LuaResult<()> {
+    replay_journal(lua, true).await
+}
+
+async fn redo_operation(lua: &Lua, _: ()) -> LuaResult<()> {
+    replay_journal(lua, false).await
+}
+
+/// Runs as a job like the operations themselves, the step returns to the journal once it's done
+async fn replay_journal(lua: &Lua, undo: bool) -> LuaResult<()> {
+    // Checked first, the step can't be taken off the journal for a job that's refused
+    if Job::is_busy(lua).await? {
+        return Ok(());
     }
 
-    *selection = HashMap::new();
-    instance.close_selection_popup(lua, &selection).await?;
-    instance.set_buffer_content(lua, &selection).await
+    let replay = CONTAINER.journal.write().await.take(undo);
+
+    match replay {
+        Ok(replay) => Job::start(lua, JobKind::Journal(replay)).await.map(|_| ()),
+        Err(err) => NeoApi::notify(lua, &err),
+    }
 }
 
 async fn undo_selection(lua: &Lua, _: ()) -> LuaResult<()> {
@@ -416,14 +1030,14 @@ async fn undo_selection(lua: &Lua, _: ()) -> LuaResult<()> {
     *selection = HashMap::new();
 
     let mut instances = CONTAINER.instances.write().await;
-    let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+    let instance = AppState::active_instance_mut(&mut instances)?;
 
     instance.close_selection_popup(lua, &selection).await
 }
 
 async fn toggle_hidden(lua: &Lua, _: ()) -> LuaResult<()> {
     let mut instances = CONTAINER.instances.write().await;
-    let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+    let instance = AppState::active_instance_mut(&mut instances)?;
 
     instance.show_hidden = !instance.show_hidden;
 
@@ -431,31 +1045,75 @@ async fn toggle_hidden(lua: &Lua, _: ()) -> LuaResult<()> {
     instance.set_buffer_content(lua, &selection).await
 }
 
+async fn toggle_ignored(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    instance.show_ignored = !instance.show_ignored;
+
+    let selection = CONTAINER.selection.read().await;
+    instance.set_buffer_content(lua, &selection).await
+}
+
+async fn toggle_details(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    instance.show_details = !instance.show_details;
+
+    let selection = CONTAINER.selection.read().await;
+    instance.reload_buffer(lua, &selection).await
+}
+
+async fn cycle_sort_key(lua: &Lua, _: ()) -> LuaResult<()> {
+    update_sort(lua, |sort| sort.key = sort.key.next()).await
+}
+
+async fn reverse_sort_order(lua: &Lua, _: ()) -> LuaResult<()> {
+    update_sort(lua, |sort| sort.descending = !sort.descending).await
+}
+
+/// Resorts the listing of the active instance, the mode is shown in the file name
+async fn update_sort(lua: &Lua, update: impl FnOnce(&mut SortMode)) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    update(&mut instance.sort);
+
+    let buf_id = instance.buf.id();
+    NeoApi::set_cmd_file(lua, format!("Traveller ({buf_id}) [{}]", instance.sort))?;
+
+    let selection = CONTAINER.selection.read().await;
+    instance.reload_buffer(lua, &selection).await
+}
+
 async fn navigate_to_parent(lua: &Lua, _: ()) -> LuaResult<()> {
     let mut instances = CONTAINER.instances.write().await;
-    let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+    let instance = AppState::active_instance_mut(&mut instances)?;
 
-    if instance.cwd.parent().is_none() {
+    let selection = CONTAINER.selection.read().await;
+
+    if instance.tree && instance.collapse(lua, &selection).await? {
         return Ok(());
     }
 
-    if !instance.buf_content.is_empty() {
-        instance.update_history(instance.get_item(lua)?);
+    let Some(item) = instance.cwd.file_name() else {
+        return Ok(());
+    };
+
+    let item = NeoUtils::escape_name(item);
+
+    if let Some(item) = instance.item_under_cursor(lua)? {
+        let item = item.relative_name(&instance.cwd);
+        instance.update_history(item).await;
     }
 
     // Before navigating to parent add to history to the parent directory already knows to which it
     // needs to point its cursor
-    let item = instance
-        .cwd
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .to_string();
 
     instance.cwd.pop();
-    instance.update_history(format!("{item}/"));
+    instance.update_history(format!("{item}/")).await;
 
-    let selection = CONTAINER.selection.read().await;
     instance.set_buffer_content(lua, &selection).await
 }
 
@@ -477,28 +1135,50 @@ async fn open_item_in_hsplit(lua: &Lua, _: ()) -> LuaResult<()> {
 
 async fn open_item(lua: &Lua, open_in: OpenIn) -> LuaResult<()> {
     let mut instances = CONTAINER.instances.write().await;
-    let instance = instances.get_mut(&AppState::active_buf()).unwrap();
+    let instance = AppState::active_instance_mut(&mut instances)?;
 
     let cursor = NeoWindow::CURRENT.get_cursor(lua)?;
 
     let item = instance
         .buf_content
         .get(cursor.row_zero_indexed() as usize)
-        .map(|item| item.to_string());
+        .cloned();
 
     // Empty directory
-    if item.is_none() {
+    let Some(item) = item else {
         return Ok(());
+    };
+
+    if item.path.is_symlink() && !item.path.exists() {
+        let msg = format!("{} is a broken link", item.name);
+        return Err(TravellerError::InvalidInput(msg).into());
     }
 
-    let item = item.unwrap();
+    // Reading a named pipe or a device would block Neovim
+    let kind = FileKind::read(&item.path);
 
-    if item.ends_with('/') {
-        instance.cwd.push(&item);
-        let selection = CONTAINER.selection.read().await;
+    if kind.is_special() {
+        let msg = format!(
+            "{} is a {}, it can't be opened",
+            item.name,
+            kind.description()
+        );
+        return Err(TravellerError::InvalidInput(msg).into());
+    }
+
+    let selection = CONTAINER.selection.read().await;
+
+    if item.is_dir() && instance.tree && !instance.expanded.contains(&item.path) {
+        instance.expand(lua, &item, &selection).await?;
+    } else if item.is_dir() {
+        instance.cwd = item.path;
         instance.set_buffer_content(lua, &selection).await?;
     } else {
-        NeoApi::open_file(lua, open_in, &item)?;
+        // Reopening the directory puts the cursor on the file again
+        instance
+            .update_history(item.relative_name(&instance.cwd))
+            .await;
+        open_path(lua, open_in, &item.path)?;
 
         if let Some(git_root) = NeoUtils::git_root(&instance.cwd) {
             NeoApi::set_cwd(lua, &git_root)?;
@@ -509,8 +1189,13 @@ async fn open_item(lua: &Lua, open_in: OpenIn) -> LuaResult<()> {
 }
 
 async fn close_navigation(lua: &Lua, _: ()) -> LuaResult<()> {
-    let instances = CONTAINER.instances.read().await;
-    let instance = instances.get(&AppState::active_buf()).unwrap();
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    if let Some(item) = instance.item_under_cursor(lua)? {
+        let item = item.relative_name(&instance.cwd);
+        instance.update_history(item).await;
+    }
 
     let path = instance.started_from.clone();
 
@@ -520,55 +1205,116 @@ async fn close_navigation(lua: &Lua, _: ()) -> LuaResult<()> {
 
     drop(instances);
 
-    NeoApi::open_file(lua, OpenIn::Buffer, path.to_str().unwrap())
+    open_path(lua, OpenIn::Buffer, &path)
 }
 
-fn nav_buffer_lines(path: &PathBuf, show_hidden: bool) -> LuaResult<Vec<String>> {
-    let dir = fs::read_dir(path).map_err(LuaError::external)?;
+/// Paths that aren't valid UTF-8 are passed to Neovim as they are, instead of lossy
+pub fn open_path(lua: &Lua, open_in: OpenIn, path: &Path) -> LuaResult<()> {
+    if let Some(path) = path.to_str() {
+        return NeoApi::open_file(lua, open_in, path);
+    }
 
-    let mut paths: Vec<_> = dir
-        .map(|item| item.unwrap())
-        .filter(|path| {
-            let hidden_file = path
-                .file_name()
-                .to_str()
-                .map(|file| file.starts_with('.'))
-                .unwrap_or(false);
+    let vim: LuaTable = lua.globals().get("vim")?;
+    let fnameescape: LuaFunction = vim.get::<_, LuaTable>("fn")?.get("fnameescape")?;
+    let command: LuaFunction = vim.get::<_, LuaTable>("api")?.get("nvim_command")?;
 
-            !hidden_file || show_hidden
-        })
-        .collect();
+    let escaped: LuaString = fnameescape.call(lua.create_string(path.as_os_str().as_bytes())?)?;
 
-    paths.sort_by(|a, b| {
-        let met_a = a.metadata().unwrap();
-        let met_b = b.metadata().unwrap();
+    let cmd = match open_in {
+        OpenIn::Buffer => "edit ",
+        OpenIn::Tab => "tabedit ",
+        OpenIn::VSplit => "vsplit ",
+        OpenIn::HSplit => "split ",
+    };
 
-        if met_a.is_dir() == met_b.is_dir() {
-            a.file_name().cmp(&b.file_name())
-        } else if met_a.is_dir() {
-            Ordering::Less
-        } else {
-            Ordering::Greater
-        }
-    });
+    let cmd = [cmd.as_bytes(), escaped.as_bytes()].concat();
+    command.call(lua.create_string(&cmd)?)
+}
 
-    let mut lines = vec![];
+pub fn nav_items(
+    dir_path: &Path,
+    depth: usize,
+    show_hidden: bool,
+    sort: SortMode,
+    case_insensitive: bool,
+) -> TravellerResult<Vec<NavItem>> {
+    let dir = fs::read_dir(dir_path).map_err(TravellerError::io(dir_path))?;
+
+    let mut entries: Vec<_> = dir
+        .filter_map(|item| item.ok())
+        .filter(|entry| show_hidden || !entry.file_name().as_bytes().starts_with(b"."))
+        .map(ListedEntry::read)
+        .collect();
 
-    for entry in paths {
-        append_item(entry, &mut lines);
-    }
+    sort.sort(&mut entries, case_insensitive);
 
-    Ok(lines)
+    Ok(entries
+        .into_iter()
+        .map(|entry| listed_item(entry, depth))
+        .collect())
 }
 
-fn append_item(entry: DirEntry, lines: &mut Vec<String>) {
-    if let Ok(file_type) = entry.file_type() {
-        let name = entry.file_name().to_string_lossy().to_string();
+/// The name is escaped for the buffer, the path keeps the exact bytes. Symlinks are followed, so
+/// links to directories are navigated into
+fn listed_item(entry: ListedEntry, depth: usize) -> NavItem {
+    let kind = entry
+        .metadata
+        .as_ref()
+        .map(FileKind::from_metadata)
+        .unwrap_or_default();
+
+    let name = if kind == FileKind::Dir {
+        format!("{}/", entry.name)
+    } else {
+        entry.name
+    };
+
+    // The details show the link itself, not what it points to
+    let metadata = entry.metadata.filter(|_| !entry.is_symlink);
+
+    NavItem {
+        path: entry.path,
+        name,
+        depth,
+        kind,
+        metadata,
+    }
+}
 
-        if file_type.is_dir() {
-            lines.push(format!("{name}/"));
-        } else {
-            lines.push(name);
-        }
+#[cfg(test)]
+mod test {
+    use super::{Listing, ListingOptions};
+    use crate::sort::SortMode;
+    use crate::test_utils::TempDir;
+    use std::{fs, process::Command};
+
+    #[test]
+    pub fn broken_git_repository() {
+        let root = TempDir::new();
+        let status = Command::new("git")
+            .arg("init")
+            .arg("-q")
+            .arg(&*root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        fs::write(root.join("a.txt"), "").unwrap();
+        fs::write(root.join(".git/index"), "garbage").unwrap();
+
+        let options = ListingOptions {
+            show_hidden: false,
+            show_ignored: false,
+            sort: SortMode::default(),
+            case_insensitive: false,
+            git_status: true,
+            expanded: None,
+        };
+
+        // Still listed, without the git decorations
+        let listing = Listing::read(&root, &options).unwrap();
+        assert_eq!(1, listing.items.len());
+        assert!(listing.git_status.is_empty());
+        assert!(listing.git_error.is_some());
     }
 }
@@ -1,11 +1,25 @@
-use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::fs;
+use std::ops::Range;
+use std::time::SystemTime;
 
-use crate::state::{AppInstance, SelectionData};
+use crate::config::Config;
+use crate::details::EntryDetails;
+use crate::file_kind;
+use crate::filter;
+use crate::git_status;
+use crate::icons;
+use crate::links::{self, SymlinkTarget};
+use crate::ls_colors::LS_COLORS;
+use crate::state::{AppInstance, NavItem, SelectionData};
+use crate::tree::TREE_INDENT;
+use crate::utils::NeoUtils;
 use crate::CONTAINER;
 use neo_api_rs::mlua::prelude::*;
 use neo_api_rs::*;
 
+/// Rows themed around the visible ones at least, so short scrolls don't theme again
+const MIN_THEME_MARGIN: usize = 64;
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Theme {
     pub navigation_ns: u32,
@@ -21,7 +35,11 @@ impl Theme {
         self.help_ns = NeoTheme::create_namespace(lua, "TravellerHelp")?;
         self.status_ns = NeoTheme::create_namespace(lua, "TravellerStatus")?;
 
-        Ok(())
+        icons::define_icon_highlights(lua)?;
+        git_status::define_git_highlights(lua)?;
+        file_kind::define_kind_highlights(lua)?;
+        links::define_link_highlights(lua)?;
+        LS_COLORS.define_highlights(lua)
     }
 }
 
@@ -36,12 +54,14 @@ impl AppInstance {
             .clear_namespace(lua, theme.navigation_ns as i32, 0, -1)?;
 
         if self.buf_content.is_empty() {
-            let ui = &NeoApi::list_uis(lua)?[0];
+            // Headless instances have no UI to center in
+            let ui_width = NeoApi::list_uis(lua)?.first().map_or(0, |ui| ui.width);
             self.win.set_option_value(lua, "cursorline", false)?;
 
             let text = "Traveller - (Empty directory)";
             let width = text.len() as u32;
-            let center = ((ui.width - width) as f32 * 0.5).round() as u32 - 2;
+            let center = (ui_width.saturating_sub(width) as f32 * 0.5).round() as u32;
+            let center = center.saturating_sub(2);
 
             let hl_text = HLText::new(text, "Comment");
 
@@ -59,26 +79,358 @@ impl AppInstance {
             self.win.set_option_value(lua, "cursorline", true)?;
         }
 
-        for (i, item_name) in self.buf_content.iter().enumerate() {
-            if item_name.ends_with('/') {
+        let config = CONTAINER.config.read().await;
+
+        let (top, bottom) = visible_rows(lua, &self.win)?;
+        let rows = themed_rows(top, bottom, self.buf_content.len());
+        self.themed_rows = rows.clone();
+
+        // Rows don't match the details or links while the listing is being edited
+        let links = if self.is_editing() {
+            vec![]
+        } else {
+            self.symlink_targets(&rows)
+        };
+
+        if !self.is_editing() {
+            self.theme_git_status(lua, theme.navigation_ns, &rows)?;
+        }
+
+        if self.show_details && !self.is_editing() {
+            // Inline icons push the names two cells to the right, indicators one
+            let icon_offset = if config.icons { 2 } else { 0 };
+            let indicators = config.type_indicators
+                && self
+                    .buf_content
+                    .iter()
+                    .any(|item| item.kind.indicator().is_some());
+            let offset = icon_offset + usize::from(indicators);
+            let ns = theme.navigation_ns;
+            self.theme_details(lua, ns, offset, config.relative_time, &links, &rows)?;
+        } else if !self.is_editing() {
+            for (row, item) in self.rows(&rows) {
+                let mut virt_text = vec![];
+
+                if let Some(size) = self.dir_size(item) {
+                    virt_text.push(HLText::new(NeoUtils::human_size(size), "Number"));
+                }
+
+                if let Some(Some(link)) = links.get(row) {
+                    virt_text.push(link_text(link));
+                }
+
+                if virt_text.is_empty() {
+                    continue;
+                }
+
+                let opts = ExtmarkOpts {
+                    virt_text: Some(virt_text),
+                    virt_text_pos: Some(VirtTextPos::Eol),
+                    ..Default::default()
+                };
+
+                self.buf
+                    .set_extmarks(lua, theme.navigation_ns, row as u32, 0, opts)?;
+            }
+        }
+
+        for (i, item) in self.rows(&rows) {
+            if item.is_dir() {
                 self.buf
                     .add_highlight(lua, theme.navigation_ns as i32, "Directory", i, 0, -1)?;
             }
 
-            if self.is_selected(selection, item_name) {
+            // Ignored items don't get their own colours, so they don't stand out
+            if self.ignored.contains(&item.path) {
+                self.buf
+                    .add_highlight(lua, theme.navigation_ns as i32, "Comment", i, 0, -1)?;
+            } else if let Some(hl_group) = name_highlight(item, &config) {
+                self.buf
+                    .add_highlight(lua, theme.navigation_ns as i32, &hl_group, i, 0, -1)?;
+            }
+
+            let link_hl = links.get(i).and_then(|link| link.as_ref()?.hl_group());
+
+            if let Some(hl_group) = link_hl {
+                self.buf
+                    .add_highlight(lua, theme.navigation_ns as i32, hl_group, i, 0, -1)?;
+            }
+
+            // Edited lines don't match the rows they started from
+            if config.icons && !self.is_editing() {
+                let icon = icons::file_icon(&item.name);
+                let col = (item.depth * TREE_INDENT.len()) as u32;
+
+                let opts = ExtmarkOpts {
+                    virt_text: Some(vec![HLText::new(format!("{} ", icon.icon), icon.hl_group)]),
+                    virt_text_pos: Some(VirtTextPos::Inline),
+                    ..Default::default()
+                };
+
+                self.buf
+                    .set_extmarks(lua, theme.navigation_ns, i as u32, col, opts)?;
+            }
+
+            let indicator = item.kind.indicator().filter(|_| config.type_indicators);
+
+            if let Some(indicator) = indicator.filter(|_| !self.is_editing()) {
+                let hl_group = item.kind.hl_group().unwrap_or("Comment");
+
+                let opts = ExtmarkOpts {
+                    virt_text: Some(vec![HLText::new(indicator, hl_group)]),
+                    virt_text_pos: Some(VirtTextPos::Inline),
+                    ..Default::default()
+                };
+
+                let col = item.line().len() as u32;
+                self.buf
+                    .set_extmarks(lua, theme.navigation_ns, i as u32, col, opts)?;
+            }
+
+            if is_selected(selection, item) {
                 self.buf
                     .add_highlight(lua, theme.navigation_ns as i32, "Special", i, 0, -1)?;
             }
+
+            let matches = self
+                .filter
+                .as_ref()
+                .filter(|_| !self.is_editing())
+                .and_then(|filter| filter::match_positions(&filter.query, &item.name));
+
+            for range in matches.unwrap_or_default() {
+                let indent = item.depth * TREE_INDENT.len();
+                let (start, end) = (indent + range.start, indent + range.end);
+
+                self.buf.add_highlight(
+                    lua,
+                    theme.navigation_ns as i32,
+                    "Search",
+                    i,
+                    start,
+                    end as i32,
+                )?;
+            }
         }
 
         Ok(())
     }
 
-    fn is_selected(&self, selection: &HashMap<PathBuf, HashSet<String>>, item_name: &str) -> bool {
-        if let Some(cwd) = selection.get(&self.cwd) {
-            cwd.contains(item_name)
-        } else {
-            false
+    /// Themes the rows that scrolled into view, unless they were themed already
+    pub fn scrolled(&self, lua: &Lua) -> LuaResult<bool> {
+        let (top, bottom) = visible_rows(lua, &self.win)?;
+        let bottom = bottom.min(self.buf_content.len().saturating_sub(1));
+
+        Ok(!self.themed_rows.contains(&top) || !self.themed_rows.contains(&bottom))
+    }
+
+    fn rows(&self, rows: &Range<usize>) -> impl Iterator<Item = (usize, &NavItem)> {
+        self.buf_content
+            .iter()
+            .enumerate()
+            .skip(rows.start)
+            .take(rows.len())
+    }
+
+    /// Renders the detail columns as virtual text after the names, aligned over all rows
+    fn theme_details(
+        &self,
+        lua: &Lua,
+        ns: u32,
+        offset: usize,
+        relative: bool,
+        links: &[Option<SymlinkTarget>],
+        rows: &Range<usize>,
+    ) -> LuaResult<()> {
+        let now = SystemTime::now();
+
+        let name_width = self
+            .buf_content
+            .iter()
+            .map(|item| item.line().chars().count())
+            .max()
+            .unwrap_or(0);
+
+        let details: Vec<_> = self.details.iter().flatten().collect();
+        let width = |column: fn(&EntryDetails) -> &str| {
+            details
+                .iter()
+                .map(|details| column(details).chars().count())
+                .max()
+                .unwrap_or(0)
+        };
+
+        let owner_width = width(|details| &details.owner);
+        let group_width = width(|details| &details.group);
+        // Directory sizes that arrived replace the `-` of their row
+        let sizes: Vec<_> = self
+            .buf_content
+            .iter()
+            .map(|item| self.dir_size(item).map(NeoUtils::human_size))
+            .collect();
+
+        let size_width = sizes
+            .iter()
+            .flatten()
+            .map(|size| size.chars().count())
+            .fold(width(|details| &details.size), usize::max);
+
+        for (row, details) in self.details.iter().enumerate() {
+            if !rows.contains(&row) {
+                continue;
+            }
+
+            let Some(details) = details else {
+                continue;
+            };
+
+            let size = sizes.get(row).cloned().flatten();
+            let size = size.as_deref().unwrap_or(&details.size);
+
+            let mut virt_text = vec![
+                HLText::new(format!("{}  ", details.mode), "Comment"),
+                HLText::new(format!("{:<owner_width$} ", details.owner), "Constant"),
+                HLText::new(format!("{:<group_width$}  ", details.group), "Constant"),
+                HLText::new(format!("{size:>size_width$}  "), "Number"),
+                HLText::new(details.modified(relative, now), "Comment"),
+            ];
+
+            if let Some(Some(link)) = links.get(row) {
+                virt_text.push(HLText::new("  ", "Comment"));
+                virt_text.push(link_text(link));
+            }
+
+            let opts = ExtmarkOpts {
+                virt_text: Some(virt_text),
+                virt_text_win_col: Some((name_width + offset) as u32 + 2),
+                ..Default::default()
+            };
+
+            self.buf.set_extmarks(lua, ns, row as u32, 0, opts)?;
+        }
+
+        Ok(())
+    }
+
+    fn theme_git_status(&self, lua: &Lua, ns: u32, rows: &Range<usize>) -> LuaResult<()> {
+        for (row, item) in self.rows(rows) {
+            let Some(status) = self.git_status.get(&item.path) else {
+                continue;
+            };
+
+            let opts = ExtmarkOpts {
+                virt_text: Some(vec![HLText::new(status.marker(), status.hl_group())]),
+                virt_text_pos: Some(VirtTextPos::RightAlign),
+                ..Default::default()
+            };
+
+            self.buf.set_extmarks(lua, ns, row as u32, 0, opts)?;
+        }
+
+        Ok(())
+    }
+
+    fn dir_size(&self, item: &NavItem) -> Option<u64> {
+        if !self.show_dir_sizes || !item.is_dir() {
+            return None;
         }
+
+        self.dir_sizes.as_ref()?.get(&item.path)
+    }
+
+    /// Targets by row, only read for the themed rows. The git root is only looked up when
+    /// there are symlinks
+    fn symlink_targets(&self, rows: &Range<usize>) -> Vec<Option<SymlinkTarget>> {
+        if !self.rows(rows).any(|(_, item)| item.path.is_symlink()) {
+            return vec![];
+        }
+
+        let git_root = NeoUtils::git_root(&self.cwd).and_then(|root| fs::canonicalize(root).ok());
+
+        self.buf_content
+            .iter()
+            .enumerate()
+            .map(|(row, item)| {
+                rows.contains(&row)
+                    .then(|| SymlinkTarget::read(&item.path, git_root.as_deref()))
+                    .flatten()
+            })
+            .collect()
+    }
+}
+
+/// First and last visible row of the window, zero indexed
+fn visible_rows(lua: &Lua, win: &NeoWindow) -> LuaResult<(usize, usize)> {
+    let vim_fn: LuaTable = lua.globals().get::<_, LuaTable>("vim")?.get("fn")?;
+    let line: LuaFunction = vim_fn.get("line")?;
+
+    // Zero when the window isn't shown
+    let top: usize = line.call(("w0", win.id()))?;
+    let bottom: usize = line.call(("w$", win.id()))?;
+
+    Ok((top.saturating_sub(1), bottom.saturating_sub(1)))
+}
+
+/// The visible rows with a screen above and below them, highlighting a whole listing of a huge
+/// directory would block the editor
+fn themed_rows(top: usize, bottom: usize, len: usize) -> Range<usize> {
+    let margin = (bottom.saturating_sub(top) + 1).max(MIN_THEME_MARGIN);
+    let start = top.saturating_sub(margin);
+    let end = bottom.saturating_add(margin + 1).min(len);
+
+    start..end.max(start)
+}
+
+/// The configured colour of the extension, otherwise the one of `LS_COLORS` or of the file kind
+fn name_highlight(item: &NavItem, config: &Config) -> Option<String> {
+    if !item.is_dir() {
+        let extension = item
+            .path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+
+        if let Some(hl_group) = extension.and_then(|ext| config.colors.get(&ext)) {
+            return Some(hl_group.clone());
+        }
+    }
+
+    let ls_colors = config
+        .ls_colors
+        .then(|| LS_COLORS.hl_group(&item.path))
+        .flatten();
+
+    ls_colors.or_else(|| item.kind.hl_group().map(String::from))
+}
+
+fn is_selected(selection: &SelectionData, item: &NavItem) -> bool {
+    selection
+        .get(item.dir())
+        .is_some_and(|names| names.contains(&item.name))
+}
+
+fn link_text(link: &SymlinkTarget) -> HLText {
+    let hl_group = link.hl_group().unwrap_or("Comment");
+    HLText::new(format!("-> {}", link.target.display()), hl_group)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{themed_rows, MIN_THEME_MARGIN};
+
+    #[test]
+    pub fn visible_theme_rows() {
+        assert_eq!(0..10, themed_rows(0, 9, 10));
+        assert_eq!(0..MIN_THEME_MARGIN + 40, themed_rows(0, 39, 200_000));
+
+        let margin = MIN_THEME_MARGIN;
+        assert_eq!(
+            1000 - margin..1039 + margin + 1,
+            themed_rows(1000, 1039, 200_000)
+        );
+
+        // Screens taller than the minimum margin get a whole screen around them
+        assert_eq!(900..1200, themed_rows(1000, 1099, 200_000));
+        assert_eq!(199_800..200_000, themed_rows(199_900, 199_999, 200_000));
+        assert_eq!(0..0, themed_rows(0, 0, 0));
     }
 }
@@ -0,0 +1,292 @@
+use crate::utils::NeoUtils;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Trash can following the FreeDesktop trash specification
+/// (https://specifications.freedesktop.org/trash-spec/trashspec-latest.html)
+#[derive(Debug, Clone)]
+pub struct Trash {
+    pub files_dir: PathBuf,
+    pub info_dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrashedItem {
+    pub original: PathBuf,
+    pub trashed: PathBuf,
+    pub info: PathBuf,
+}
+
+impl Trash {
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        let trash = Self {
+            files_dir: root.join("files"),
+            info_dir: root.join("info"),
+        };
+
+        fs::create_dir_all(&trash.files_dir)?;
+        fs::create_dir_all(&trash.info_dir)?;
+
+        Ok(trash)
+    }
+
+    /// The home trash: `$XDG_DATA_HOME/Trash`
+    pub fn home() -> io::Result<Self> {
+        let data_home = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+
+        Self::new(data_home.join("Trash"))
+    }
+
+    /// Moves the item to the home trash, or to the trash of the top directory of its filesystem
+    /// when it lives on another one
+    pub fn put(path: &Path) -> io::Result<TrashedItem> {
+        let home = Self::home()?;
+        let item_dev = fs::symlink_metadata(path)?.dev();
+
+        if fs::metadata(&home.files_dir)?.dev() == item_dev {
+            return home.put_item(path);
+        }
+
+        let uid = unsafe { libc::getuid() };
+        let topdir = Self::topdir(path, item_dev)?;
+
+        Self::topdir_trash(&topdir, uid)?.put_item(path)
+    }
+
+    /// `$topdir/.Trash/$uid` when an administrator has set up `$topdir/.Trash` for everyone,
+    /// `$topdir/.Trash-$uid` otherwise
+    fn topdir_trash(topdir: &Path, uid: u32) -> io::Result<Self> {
+        let shared = topdir.join(".Trash");
+
+        // Must have the sticky bit and can't be a symlink, otherwise users could take over each
+        // other's trash
+        let usable = fs::symlink_metadata(&shared)
+            .is_ok_and(|metadata| metadata.is_dir() && metadata.mode() & 0o1000 != 0);
+
+        if usable {
+            if let Ok(trash) = Self::new(shared.join(uid.to_string())) {
+                return Ok(trash);
+            }
+        }
+
+        Self::new(topdir.join(format!(".Trash-{uid}")))
+    }
+
+    pub fn put_item(&self, path: &Path) -> io::Result<TrashedItem> {
+        let original = std::path::absolute(path)?;
+        let name = original
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Can't trash root"))?
+            .to_os_string();
+
+        let deletion_date = NeoUtils::format_local(SystemTime::now());
+
+        for i in 1..=u32::MAX {
+            let mut trash_name = name.clone();
+
+            if i > 1 {
+                trash_name.push(format!(".{i}"));
+            }
+
+            let mut info_name = trash_name.clone();
+            info_name.push(".trashinfo");
+
+            let info = self.info_dir.join(info_name);
+            let trashed = self.files_dir.join(&trash_name);
+
+            // The info file is created atomically to claim the name
+            let mut info_file = match OpenOptions::new().write(true).create_new(true).open(&info) {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            };
+
+            if trashed.symlink_metadata().is_ok() {
+                drop(info_file);
+                fs::remove_file(&info)?;
+                continue;
+            }
+
+            let content = format!(
+                "[Trash Info]\nPath={}\nDeletionDate={deletion_date}\n",
+                percent_encode(&original)
+            );
+
+            let result = info_file
+                .write_all(content.as_bytes())
+                .and_then(|_| fs::rename(&original, &trashed));
+
+            if let Err(err) = result {
+                let _ = fs::remove_file(&info);
+                return Err(err);
+            }
+
+            return Ok(TrashedItem {
+                original,
+                trashed,
+                info,
+            });
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("No free name left in the trash for {}", original.display()),
+        ))
+    }
+
+    /// Moves a trashed item back to where it came from
+    pub fn restore(item: &TrashedItem) -> io::Result<()> {
+        if item.original.symlink_metadata().is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", item.original.display()),
+            ));
+        }
+
+        fs::rename(&item.trashed, &item.original)?;
+        fs::remove_file(&item.info)
+    }
+
+    /// Highest directory above the item on the same filesystem
+    fn topdir(path: &Path, dev: u64) -> io::Result<PathBuf> {
+        let path = std::path::absolute(path)?;
+        // The item itself can be the root of its filesystem
+        let mut topdir = path.as_path();
+
+        while let Some(parent) = topdir.parent() {
+            if fs::metadata(parent)?.dev() != dev {
+                break;
+            }
+
+            topdir = parent;
+        }
+
+        if topdir == path {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is a mount point, it can't be trashed", path.display()),
+            ));
+        }
+
+        Ok(topdir.to_path_buf())
+    }
+}
+
+fn percent_encode(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut encoded = String::new();
+
+    for byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(*byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::{percent_encode, Trash};
+    use crate::test_utils::TempDir;
+    use crate::utils::NeoUtils;
+    use std::{
+        fs,
+        os::unix::fs::{symlink, MetadataExt, PermissionsExt},
+        path::Path,
+        time::Duration,
+        time::UNIX_EPOCH,
+    };
+
+    #[test]
+    pub fn trash_info() {
+        let date = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        assert_eq!("2024-03-01T12:00:00", NeoUtils::format_utc(date));
+        assert_eq!("/tmp/a%20b%25", percent_encode(Path::new("/tmp/a b%")));
+    }
+
+    #[test]
+    pub fn trash_collision() {
+        let root = TempDir::new();
+        let trash = Trash::new(root.join("Trash")).unwrap();
+
+        let file = root.join("file.txt");
+
+        fs::write(&file, "one").unwrap();
+        let first = trash.put_item(&file).unwrap();
+
+        fs::write(&file, "two").unwrap();
+        let second = trash.put_item(&file).unwrap();
+
+        assert!(!file.exists());
+        assert_eq!(first.trashed, trash.files_dir.join("file.txt"));
+        assert_eq!(second.trashed, trash.files_dir.join("file.txt.2"));
+        assert_eq!("two", fs::read_to_string(&second.trashed).unwrap());
+
+        let info = fs::read_to_string(&second.info).unwrap();
+        assert!(info.starts_with("[Trash Info]\nPath=/"));
+    }
+
+    #[test]
+    pub fn restore_trashed() {
+        let root = TempDir::new();
+        let trash = Trash::new(root.join("Trash")).unwrap();
+
+        let file = root.join("file.txt");
+        fs::write(&file, "one").unwrap();
+        let trashed = trash.put_item(&file).unwrap();
+
+        Trash::restore(&trashed).unwrap();
+        assert_eq!("one", fs::read_to_string(&file).unwrap());
+        assert!(!trashed.info.exists());
+    }
+
+    #[test]
+    pub fn topdir_trash() {
+        let root = TempDir::new();
+        let shared = root.join(".Trash");
+        fs::create_dir(&shared).unwrap();
+
+        // Without the sticky bit the shared trash isn't used
+        let trash = Trash::topdir_trash(&root, 1000).unwrap();
+        assert_eq!(root.join(".Trash-1000/files"), trash.files_dir);
+
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o1777)).unwrap();
+        let trash = Trash::topdir_trash(&root, 1000).unwrap();
+        assert_eq!(shared.join("1000/files"), trash.files_dir);
+
+        // A symlink could point anywhere, even with the sticky bit on its target
+        let linked = TempDir::new();
+        let target = linked.join("target");
+        fs::create_dir(&target).unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o1777)).unwrap();
+        symlink(&target, linked.join(".Trash")).unwrap();
+
+        let trash = Trash::topdir_trash(&linked, 1000).unwrap();
+        assert_eq!(linked.join(".Trash-1000/files"), trash.files_dir);
+    }
+
+    #[test]
+    pub fn mount_point_topdir() {
+        let root = TempDir::new();
+        let dev = fs::metadata(&*root).unwrap().dev();
+
+        assert!(Trash::topdir(&root, dev).is_ok());
+        assert!(Trash::topdir(&root, dev + 1).is_err());
+    }
+}
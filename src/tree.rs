@@ -0,0 +1,101 @@
+use crate::state::{AppInstance, AppState, NavItem, SelectionData};
+use crate::CONTAINER;
+use neo_api_rs::mlua::prelude::*;
+use neo_api_rs::*;
+
+/// Indentation per level of the tree view
+pub const TREE_INDENT: &str = "  ";
+
+impl AppInstance {
+    pub async fn expand(
+        &mut self,
+        lua: &Lua,
+        item: &NavItem,
+        selection: &SelectionData,
+    ) -> LuaResult<()> {
+        self.expanded.insert(item.path.clone());
+
+        // Rows above the directory don't move, so the cursor stays on it
+        self.reload_buffer(lua, selection).await
+    }
+
+    /// Collapses the directory under the cursor, or else the one its row is nested in. Returns
+    /// false when there is nothing to collapse
+    pub async fn collapse(&mut self, lua: &Lua, selection: &SelectionData) -> LuaResult<bool> {
+        let Some(item) = self.item_under_cursor(lua)? else {
+            return Ok(false);
+        };
+
+        let dir = if item.is_dir() && self.expanded.contains(&item.path) {
+            item.path
+        } else if item.depth > 0 {
+            item.dir().to_path_buf()
+        } else {
+            return Ok(false);
+        };
+
+        self.expanded.remove(&dir);
+        self.reload_buffer(lua, selection).await?;
+
+        if let Some(row) = self.buf_content.iter().position(|item| item.path == dir) {
+            let cursor = WinCursor::from_zero_indexed(row as u32, 0);
+            self.win.set_cursor(lua, cursor)?;
+        }
+
+        Ok(true)
+    }
+}
+
+pub async fn toggle_tree(lua: &Lua, _: ()) -> LuaResult<()> {
+    let mut instances = CONTAINER.instances.write().await;
+    let instance = AppState::active_instance_mut(&mut instances)?;
+
+    let item = instance.item_under_cursor(lua)?;
+
+    instance.tree = !instance.tree;
+
+    let selection = CONTAINER.selection.read().await;
+    instance.reload_buffer(lua, &selection).await?;
+
+    // Keep the cursor on the item, or on the top level directory it was nested in
+    let Some(item) = item else {
+        return Ok(());
+    };
+
+    let rows = &instance.buf_content;
+    let row = rows
+        .iter()
+        .position(|row| row.path == item.path)
+        .or_else(|| {
+            rows.iter()
+                .position(|row| row.depth == 0 && item.path.starts_with(&row.path))
+        });
+
+    if let Some(row) = row {
+        let cursor = WinCursor::from_zero_indexed(row as u32, 0);
+        instance.win.set_cursor(lua, cursor)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::NavItem;
+    use std::path::Path;
+
+    #[test]
+    pub fn nested_rows() {
+        let cwd = Path::new("/project");
+        let dir = NavItem::new(&cwd.join("src"), "bin/".to_string(), 1);
+        let file = NavItem::new(&cwd.join("src/bin"), "main.rs".to_string(), 2);
+
+        assert_eq!(Path::new("/project/src/bin"), dir.path);
+        assert_eq!(Path::new("/project/src"), dir.dir());
+        assert_eq!("  bin/", dir.line());
+        assert_eq!("src/bin/", dir.relative_name(cwd));
+
+        assert_eq!("    main.rs", file.line());
+        assert_eq!("src/bin/main.rs", file.relative_name(cwd));
+    }
+}
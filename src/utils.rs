@@ -1,35 +1,250 @@
+use crate::error::{TravellerError, TravellerResult};
 use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    io::Write,
+    os::unix::ffi::{OsStrExt, OsStringExt},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 pub struct NeoUtils;
 
 impl NeoUtils {
     pub fn git_root(path: &Path) -> Option<PathBuf> {
-        let dir_path = if path.is_file() {
-            path.parent()?.to_string_lossy()
-        } else {
-            path.to_string_lossy()
-        };
+        let dir_path = if path.is_file() { path.parent()? } else { path };
+
+        let output = Self::git_output(dir_path, &["rev-parse", "--show-toplevel"]).ok()?;
+
+        Some(Self::strip_trailing_newline(&output).into())
+    }
 
+    /// Runs git inside the directory and returns its stdout
+    pub fn git_output(dir_path: &Path, args: &[&str]) -> TravellerResult<String> {
         let output = Command::new("git")
-            .args(["-C", &dir_path, "rev-parse", "--show-toplevel"])
+            .arg("-C")
+            .arg(dir_path)
+            .args(args)
             .output()
-            .ok()?;
+            .map_err(|err| TravellerError::Git(format!("failed to run git: {err}")))?;
 
         if output.status.success() {
-            let out_raw = String::from_utf8_lossy(&output.stdout);
-            let out = Self::strip_trailing_newline(&out_raw);
-
-            Some(out.into())
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
         } else {
-            None
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(TravellerError::Git(stderr.trim().to_string()))
         }
     }
 
+    /// Items of the listing that are ignored by `.gitignore`, `.git/info/exclude` or the global
+    /// excludes file. Empty outside of a repository, the root is looked up once by the caller
+    pub fn git_ignored(
+        dir_path: &Path,
+        git_root: Option<&Path>,
+        items: &[String],
+    ) -> TravellerResult<HashSet<String>> {
+        if items.is_empty() || git_root.is_none() {
+            return Ok(HashSet::new());
+        }
+
+        let mut child = Command::new("git")
+            .arg("-C")
+            .arg(dir_path)
+            .args(["check-ignore", "--stdin", "-z"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| TravellerError::Git(format!("failed to run git: {err}")))?;
+
+        let input: Vec<u8> = items
+            .iter()
+            .flat_map(|item| {
+                let name = Self::unescape_name(item.trim_end_matches('/'));
+                name.into_vec().into_iter().chain([0])
+            })
+            .collect();
+
+        // Git writes the matches while it reads, once the stdout pipe is full it stops reading.
+        // Writing from another thread lets the output be read meanwhile
+        let stdin = child.stdin.take();
+        let writer = std::thread::spawn(move || match stdin {
+            Some(mut stdin) => stdin.write_all(&input),
+            None => Ok(()),
+        });
+
+        let output = child
+            .wait_with_output()
+            .map_err(|err| TravellerError::Git(format!("failed to run git: {err}")))?;
+
+        match writer.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                return Err(TravellerError::Git(format!(
+                    "failed to write to git: {err}"
+                )));
+            }
+            Err(_) => return Err(TravellerError::Git("failed to write to git".to_string())),
+        }
+
+        // Exits with 1 when nothing is ignored
+        if !matches!(output.status.code(), Some(0 | 1)) {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(TravellerError::Git(stderr.trim().to_string()));
+        }
+
+        let ignored: HashSet<_> = output
+            .stdout
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| Self::escape_name(OsStr::from_bytes(name)))
+            .collect();
+
+        Ok(items
+            .iter()
+            .filter(|item| ignored.contains(item.trim_end_matches('/')))
+            .cloned()
+            .collect())
+    }
+
+    /// Directories are listed with a trailing slash, which would make a symlink to one resolve
+    /// to its target. Escaped names get their original bytes back
+    pub fn item_path(dir: &Path, item: &str) -> PathBuf {
+        dir.join(Self::unescape_name(item.trim_end_matches('/')))
+    }
+
+    /// Name as it's shown in a buffer, like `ls -b`: bytes that aren't valid UTF-8 and control
+    /// characters become `\xNN`, and a backslash becomes `\\` so the escaping can be undone
+    pub fn escape_name(name: &OsStr) -> String {
+        let mut escaped = String::new();
+
+        for chunk in name.as_bytes().utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match c {
+                    '\\' => escaped.push_str("\\\\"),
+                    c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+                    c => escaped.push(c),
+                }
+            }
+
+            for byte in chunk.invalid() {
+                escaped.push_str(&format!("\\x{byte:02x}"));
+            }
+        }
+
+        escaped
+    }
+
+    /// The exact name behind an escaped one, backslashes that don't start an escape are kept
+    pub fn unescape_name(name: &str) -> OsString {
+        let bytes = name.as_bytes();
+        let mut unescaped = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let escape = bytes.get(i + 1..i + 4).filter(|_| bytes[i] == b'\\');
+
+            let byte = match escape {
+                Some([b'x', hex @ ..]) => std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                _ => None,
+            };
+
+            if let Some(byte) = byte {
+                unescaped.push(byte);
+                i += 4;
+            } else if bytes[i] == b'\\' && bytes.get(i + 1) == Some(&b'\\') {
+                unescaped.push(b'\\');
+                i += 2;
+            } else {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+
+        OsString::from_vec(unescaped)
+    }
+
+    /// Falls back to the root when `HOME` isn't set
     pub fn home_directory() -> PathBuf {
-        std::env::var_os("HOME").unwrap().into()
+        std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/"))
+    }
+
+    /// Formats bytes as e.g. `1.5 MiB`
+    pub fn human_size(bytes: u64) -> String {
+        const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+        let mut size = bytes as f64;
+        let mut unit = 0;
+
+        while size >= 1024. && unit < UNITS.len() - 1 {
+            size /= 1024.;
+            unit += 1;
+        }
+
+        if unit == 0 {
+            format!("{bytes} B")
+        } else {
+            format!("{size:.1} {}", UNITS[unit])
+        }
+    }
+
+    /// Formats as `2024-03-01T12:00:00` in UTC
+    pub fn format_utc(time: SystemTime) -> String {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|dur| dur.as_secs() as i64)
+            .unwrap_or(0);
+
+        let days = secs.div_euclid(86_400);
+        let day_secs = secs.rem_euclid(86_400);
+
+        // Civil from days (Howard Hinnant)
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        format!(
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+            day_secs / 3600,
+            day_secs % 3600 / 60,
+            day_secs % 60
+        )
+    }
+
+    /// Formats as `2024-03-01T12:00:00` in the local time zone
+    pub fn format_local(time: SystemTime) -> String {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|dur| dur.as_secs() as libc::time_t)
+            .unwrap_or(0);
+
+        // Reentrant, the background jobs format times as well
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+
+        if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+            return Self::format_utc(time);
+        }
+
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        )
     }
 
     fn strip_trailing_newline(input: &str) -> &str {
@@ -39,3 +254,117 @@ impl NeoUtils {
             .unwrap_or(input)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::NeoUtils;
+    use crate::paste::unique_target;
+    use crate::sort::SortMode;
+    use crate::state::nav_items;
+    use crate::test_utils::TempDir;
+    use std::{
+        collections::HashSet,
+        ffi::OsStr,
+        fs,
+        os::unix::ffi::OsStrExt,
+        process::Command,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn ignoring_dir() -> (TempDir, Vec<String>) {
+        let root = TempDir::new();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n*.log\n").unwrap();
+        fs::write(root.join("build.log"), "").unwrap();
+
+        let items = ["target/", "build.log", "main.rs"].map(String::from).into();
+
+        (root, items)
+    }
+
+    fn git_init(root: &TempDir) {
+        let status = Command::new("git")
+            .arg("init")
+            .arg("-q")
+            .arg(&**root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    pub fn ignored_outside_repository() {
+        let (root, items) = ignoring_dir();
+
+        assert!(NeoUtils::git_ignored(&root, None, &items)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    pub fn git_ignored_items() {
+        let (root, items) = ignoring_dir();
+        git_init(&root);
+
+        assert_eq!(
+            HashSet::from(["target/".to_string(), "build.log".to_string()]),
+            NeoUtils::git_ignored(&root, Some(&root), &items).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn many_ignored_items() {
+        let (root, _) = ignoring_dir();
+        git_init(&root);
+
+        // More output than fits in a pipe while git still reads its input
+        let items: Vec<_> = (0..30_000).map(|i| format!("object-{i}.log")).collect();
+        let ignored = NeoUtils::git_ignored(&root, Some(&root), &items).unwrap();
+
+        assert_eq!(items.len(), ignored.len());
+    }
+
+    #[test]
+    pub fn escaped_names() {
+        let raw = OsStr::from_bytes(b"caf\xe9\\.txt");
+
+        assert_eq!("caf\\xe9\\\\.txt", NeoUtils::escape_name(raw));
+        assert_eq!(raw, NeoUtils::unescape_name("caf\\xe9\\\\.txt"));
+        assert_eq!("tab\\x09", NeoUtils::escape_name(OsStr::new("tab\t")));
+        assert_eq!(OsStr::new("\\q\\x"), NeoUtils::unescape_name("\\q\\x"));
+    }
+
+    #[test]
+    pub fn non_utf8_names() {
+        let root = TempDir::new();
+
+        let raw = OsStr::from_bytes(b"caf\xe9\\.txt");
+        fs::write(root.join(raw), "").unwrap();
+
+        let items = nav_items(&root, 0, false, SortMode::default(), false).unwrap();
+        assert_eq!("caf\\xe9\\\\.txt", items[0].name);
+        assert_eq!(root.join(raw), items[0].path);
+
+        let copy = unique_target(&root.join(raw));
+        assert_eq!(
+            OsStr::from_bytes(b"caf\xe9\\ (1).txt"),
+            copy.file_name().unwrap()
+        );
+    }
+
+    #[test]
+    pub fn local_time() {
+        let secs = 1_709_294_400;
+        let output = Command::new("date")
+            .arg("-d")
+            .arg(format!("@{secs}"))
+            .arg("+%Y-%m-%dT%H:%M:%S")
+            .output()
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            NeoUtils::format_local(UNIX_EPOCH + Duration::from_secs(secs))
+        );
+    }
+}